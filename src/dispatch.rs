@@ -0,0 +1,467 @@
+use crate::fs::{split_path, Fs, FsError, Kid, MAX_SYMLINK_HOPS};
+use crate::trans::{Batch, Trans, STATUS_OK};
+use fuser::FileType;
+use std::io;
+use std::str;
+
+// OnError says what handle_batch does after a command in the batch fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnError {
+    Stop,     // leave the rest of the batch unanswered
+    Continue, // run the rest anyway
+}
+
+// Session is the state one client keeps between commands. relative paths
+// resolve from its current dir, which starts out as the root.
+pub struct Session {
+    cwd: Kid,
+}
+
+impl Session {
+    pub fn new(fs: &Fs) -> Self {
+        Session { cwd: fs.root() }
+    }
+
+    pub fn cwd(&self) -> Kid {
+        self.cwd.clone()
+    }
+
+    // handle runs the command in trans against fs, writing its output as the
+    // response. the first arg names the command and the rest are its operands.
+    // a failed command gets the FsError's code as its status, and responds
+    // with "error: " and a message.
+    pub fn handle(&mut self, fs: &mut Fs, trans: &mut Trans) {
+        let args = trans.take_args(trans.num_args()).unwrap_or_default();
+        let resp = match self.run(fs, &args) {
+            Ok(out) => out,
+            Err((e, msg)) => {
+                trans.set_status(e.code());
+                format!("error: {msg}\n").into_bytes()
+            }
+        };
+        // every arg was taken above, so only a response too big can fail.
+        if let Err(e) = trans.set_resp(resp) {
+            trans.set_status(FsError::InvalidArgument.code());
+            let _ = trans.set_resp(format!("error: {e}\n").into_bytes());
+        }
+    }
+
+    // handle_batch handles each transaction in batch in order, so a cd
+    // affects the commands after it. it gives how many were run, which is
+    // fewer than all of them only if one failed and on_error is Stop.
+    pub fn handle_batch(&mut self, fs: &mut Fs, batch: &mut Batch, on_error: OnError) -> usize {
+        for (i, trans) in batch.trans.iter_mut().enumerate() {
+            self.handle(fs, trans);
+            if trans.status() != STATUS_OK && on_error == OnError::Stop {
+                return i + 1;
+            }
+        }
+        batch.trans.len()
+    }
+
+    // bad commands fail with InvalidArgument and a message saying what was wrong.
+    fn run(&mut self, fs: &mut Fs, args: &[Vec<u8>]) -> Result<Vec<u8>, (FsError, String)> {
+        let invalid = |msg: String| (FsError::InvalidArgument, msg);
+        let args = parse_args(args.iter().map(Vec::as_slice))?;
+        let (cmd, operands) = args
+            .split_first()
+            .ok_or_else(|| invalid("no command".to_owned()))?;
+        let res = match (*cmd, operands) {
+            ("pwd", []) => fs
+                .path_of(&self.cwd)
+                .map(|path| format!("{path}\n").into_bytes())
+                .ok_or(FsError::NotFound),
+            ("cd", [path]) => self.cd(fs, path),
+            ("ls", [path]) => ls(fs, self.start(fs, path), path),
+            ("cat", [path]) => cat(fs, self.start(fs, path), path),
+            ("mkdir", [path]) => mkdir(fs, self.start(fs, path), path),
+            ("rm", [path]) => rm(fs, self.start(fs, path), path),
+            ("pwd", _) => return Err(invalid("usage: pwd".to_owned())),
+            ("cd" | "ls" | "cat" | "mkdir" | "rm", _) => {
+                return Err(invalid(format!("usage: {cmd} <path>")))
+            }
+            _ => return Err(invalid(format!("unknown command {cmd}"))),
+        };
+        res.map_err(|e| (e, e.to_string()))
+    }
+
+    // validate checks that the command in trans could run, without running
+    // it or taking its args: that its paths resolve, that a new name is free
+    // and allowed, that the fs is writable, and so on. checks go in the order
+    // handle makes them, so it gives the first error handle would.
+    pub fn validate(&self, fs: &Fs, trans: &Trans) -> Result<(), FsError> {
+        let args = parse_args(trans.args()).map_err(|(e, _)| e)?;
+        let (cmd, operands) = args.split_first().ok_or(FsError::InvalidArgument)?;
+        let walk = |path: &str| {
+            fs.walk_links_from(self.start(fs, path), split_path(path), MAX_SYMLINK_HOPS)
+        };
+        let walk_parent = |path: &str| fs.walk_parent_from(self.start(fs, path), split_path(path));
+        let is_dir = |kid: &Kid| kid.read().unwrap().to_dir().is_some();
+        match (*cmd, operands) {
+            ("pwd", []) => Ok(()),
+            ("ls", [path]) => walk(path).map(drop),
+            ("cd", [path]) => match walk(path)? {
+                kid if is_dir(&kid) => Ok(()),
+                _ => Err(FsError::NotADirectory),
+            },
+            ("cat", [path]) => match walk(path)? {
+                kid if is_dir(&kid) => Err(FsError::IsADirectory),
+                _ => Ok(()),
+            },
+            ("mkdir", [path]) => match walk_parent(path)? {
+                (_, _, Some(_)) => Err(FsError::AlreadyExists),
+                (parent, name, None) => fs.check_create(&parent, &name),
+            },
+            ("rm", [path]) => match walk_parent(path)? {
+                _ if fs.read_only() => Err(FsError::ReadOnly),
+                (_, _, None) => Err(FsError::NotFound),
+                (_, _, Some(kid)) if is_dir(&kid) => Err(FsError::IsADirectory),
+                _ => Ok(()),
+            },
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+
+    // start is where path resolves from: the root if it's absolute, else the cwd.
+    fn start(&self, fs: &Fs, path: &str) -> Kid {
+        if path.starts_with('/') {
+            fs.root()
+        } else {
+            self.cwd.clone()
+        }
+    }
+
+    // cd moves the cwd to the dir at path. ".." from the root stays there.
+    fn cd(&mut self, fs: &mut Fs, path: &str) -> Result<Vec<u8>, FsError> {
+        let kid = fs.walk_links_from(self.start(fs, path), split_path(path), MAX_SYMLINK_HOPS)?;
+        if kid.read().unwrap().to_dir().is_none() {
+            return Err(FsError::NotADirectory);
+        }
+        self.cwd = kid;
+        Ok(Vec::new())
+    }
+}
+
+// handle runs the command in trans in a fresh session, so relative paths
+// resolve from the root.
+pub fn handle(fs: &mut Fs, trans: &mut Trans) {
+    Session::new(fs).handle(fs, trans)
+}
+
+// handle_batch runs the commands in batch in one fresh session.
+pub fn handle_batch(fs: &mut Fs, batch: &mut Batch, on_error: OnError) -> usize {
+    Session::new(fs).handle_batch(fs, batch, on_error)
+}
+
+// validate checks the command in trans as a fresh session would run it.
+pub fn validate(fs: &Fs, trans: &Trans) -> Result<(), FsError> {
+    Session::new(fs).validate(fs, trans)
+}
+
+// parse_args gives each arg as text, failing with InvalidArgument on bad utf-8.
+fn parse_args<'a>(args: impl Iterator<Item = &'a [u8]>) -> Result<Vec<&'a str>, (FsError, String)> {
+    args.map(|arg| str::from_utf8(arg).map_err(|e| (FsError::InvalidArgument, e.to_string())))
+        .collect()
+}
+
+// reply_result gives a response's bytes, or the error its status stands for.
+pub fn reply_result(trans: &mut Trans) -> Result<Vec<u8>, FsError> {
+    let resp = trans.take_resp();
+    match trans.status() {
+        STATUS_OK => Ok(resp),
+        code => Err(FsError::from_code(code).unwrap_or(FsError::Io(io::ErrorKind::InvalidData))),
+    }
+}
+
+// read_reply reads a transaction answered by handle from r, giving its result.
+pub fn read_reply(r: &mut impl io::Read) -> io::Result<Result<Vec<u8>, FsError>> {
+    Ok(reply_result(&mut Trans::read_from(r)?))
+}
+
+// ls lists a dir's entries in name order, one per line, with a "/" after
+// each dir. a path naming anything else lists just that name.
+fn ls(fs: &mut Fs, start: Kid, path: &str) -> Result<Vec<u8>, FsError> {
+    let comps = split_path(path);
+    let kid = fs.walk_links_from(start, comps.clone(), MAX_SYMLINK_HOPS)?;
+    if kid.read().unwrap().to_dir().is_none() {
+        let name = comps.iter().rev().find(|comp| !comp.is_empty());
+        return Ok(format!("{}\n", name.map_or(path, String::as_str)).into_bytes());
+    }
+
+    let mut out = String::new();
+    for (name, kind, _) in fs.readdir(kid)? {
+        if name != "." && name != ".." {
+            out.push_str(&name);
+            if kind == FileType::Directory {
+                out.push('/');
+            }
+            out.push('\n');
+        }
+    }
+    Ok(out.into_bytes())
+}
+
+fn cat(fs: &mut Fs, start: Kid, path: &str) -> Result<Vec<u8>, FsError> {
+    let kid = fs.walk_links_from(start, split_path(path), MAX_SYMLINK_HOPS)?;
+    fs.read_at(kid, 0, usize::MAX)
+}
+
+fn mkdir(fs: &mut Fs, start: Kid, path: &str) -> Result<Vec<u8>, FsError> {
+    let (parent, name, kid) = fs.walk_parent_from(start, split_path(path))?;
+    if kid.is_some() {
+        return Err(FsError::AlreadyExists);
+    }
+    fs.new_dir(parent, &name)?;
+    Ok(Vec::new())
+}
+
+fn rm(fs: &mut Fs, start: Kid, path: &str) -> Result<Vec<u8>, FsError> {
+    let (parent, name, _) = fs.walk_parent_from(start, split_path(path))?;
+    fs.unlink(parent, &name)?;
+    Ok(Vec::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dispatch::*;
+    use crate::fs::FsConfig;
+    use crate::trans::TransBuilder;
+
+    // command makes a transaction asking for the command in args.
+    fn command(args: &[&str]) -> Trans {
+        TransBuilder::new().args(args.iter().copied()).build()
+    }
+
+    fn demo_fs() -> Fs {
+        let mut fs = Fs::new();
+        let d1 = fs.new_dir(fs.root(), "dir1").unwrap();
+        fs.new_dir(fs.root(), "dir2").unwrap();
+        fs.new_file(d1.clone(), "f1", b"HELLO").unwrap();
+        fs.new_file(d1, "f2", b"HI").unwrap();
+        fs
+    }
+
+    fn call(fs: &mut Fs, args: &[&str]) -> String {
+        let mut trans = command(args);
+        handle(fs, &mut trans);
+        let n = trans.resp_remaining();
+        String::from_utf8(trans.read_resp(n)).unwrap()
+    }
+
+    #[test]
+    fn test_handle() {
+        let mut fs = demo_fs();
+        assert_eq!(call(&mut fs, &["cat", "/dir1/f1"]), "HELLO");
+        assert_eq!(call(&mut fs, &["ls", "/dir1"]), "f1\nf2\n");
+
+        assert_eq!(call(&mut fs, &["mkdir", "/dir2/new"]), "");
+        assert_eq!(call(&mut fs, &["rm", "/dir1/f2"]), "");
+        assert_eq!(call(&mut fs, &["ls", "/"]), "dir1/\ndir2/\n");
+        assert_eq!(call(&mut fs, &["ls", "/dir2"]), "new/\n");
+        assert_eq!(call(&mut fs, &["ls", "/dir1"]), "f1\n");
+    }
+
+    #[test]
+    fn test_ls() {
+        let mut fs = demo_fs();
+        let d1 = fs.walk(split_path("/dir1")).unwrap();
+        fs.new_dir(d1.clone(), "sub").unwrap();
+        fs.symlink(d1, "link", "/dir2").unwrap();
+        assert_eq!(call(&mut fs, &["ls", "/dir1"]), "f1\nf2\nlink\nsub/\n");
+        assert_eq!(call(&mut fs, &["ls", "/dir1/link"]), "");
+        assert_eq!(call(&mut fs, &["ls", "/dir1/f2"]), "f2\n");
+        assert_eq!(call(&mut fs, &["ls", "/dir1/f2/"]), "f2\n");
+        assert_eq!(call(&mut fs, &["ls", "/bogus"]), "error: not found\n");
+    }
+
+    #[test]
+    fn test_session_cwd() {
+        let mut fs = Fs::new();
+        fs.mkdir_all("/a/b").unwrap();
+        let mut session = Session::new(&fs);
+        let mut call = |fs: &mut Fs, args: &[&str]| {
+            let mut trans = command(args);
+            session.handle(fs, &mut trans);
+            let n = trans.resp_remaining();
+            String::from_utf8(trans.read_resp(n)).unwrap()
+        };
+        assert_eq!(call(&mut fs, &["cd", "/a"]), "");
+        assert_eq!(call(&mut fs, &["pwd"]), "/a\n");
+        assert_eq!(call(&mut fs, &["ls", "b"]), "");
+        assert_eq!(call(&mut fs, &["mkdir", "b/c"]), "");
+        assert_eq!(call(&mut fs, &["ls", "b"]), "c/\n");
+        assert_eq!(call(&mut fs, &["ls", "/"]), "a/\n");
+
+        assert_eq!(call(&mut fs, &["cd", "b/c"]), "");
+        assert_eq!(call(&mut fs, &["pwd"]), "/a/b/c\n");
+        assert_eq!(call(&mut fs, &["cd", "../../.."]), "");
+        assert_eq!(call(&mut fs, &["cd", ".."]), "");
+        assert_eq!(call(&mut fs, &["pwd"]), "/\n");
+        assert_eq!(call(&mut fs, &["cd", "bogus"]), "error: not found\n");
+        assert_eq!(call(&mut fs, &["pwd"]), "/\n");
+    }
+
+    #[test]
+    fn test_handle_too_big() {
+        let mut fs = demo_fs();
+        let mut trans = Trans::with_limits(2, 64);
+        trans.add_arg(b"cat".to_vec()).unwrap();
+        trans.add_arg(b"/dir1/f1".to_vec()).unwrap();
+        handle(&mut fs, &mut trans);
+        assert_eq!(trans.read_resp(64), b"HELLO");
+
+        fs.write_file(fs.walk(split_path("/dir1/f1")).unwrap(), 0, &[b'x'; 100])
+            .unwrap();
+        trans.reset();
+        trans.add_arg(b"cat".to_vec()).unwrap();
+        trans.add_arg(b"/dir1/f1".to_vec()).unwrap();
+        handle(&mut fs, &mut trans);
+        assert_eq!(trans.read_resp(64), b"error: transaction limit exceeded\n");
+    }
+
+    #[test]
+    fn test_handle_status() {
+        let mut fs = demo_fs();
+        let reply = |fs: &mut Fs, args: &[&str]| {
+            let mut trans = command(args);
+            handle(fs, &mut trans);
+            read_reply(&mut io::Cursor::new(trans.encode())).unwrap()
+        };
+        assert_eq!(reply(&mut fs, &["cat", "/dir1/f1"]), Ok(b"HELLO".to_vec()));
+        assert_eq!(reply(&mut fs, &["cat", "/missing"]), Err(FsError::NotFound));
+        assert_eq!(reply(&mut fs, &["ls", "/nope"]), Err(FsError::NotFound));
+        assert_eq!(
+            reply(&mut fs, &["cat", "/dir1"]),
+            Err(FsError::IsADirectory)
+        );
+        assert_eq!(reply(&mut fs, &["cat"]), Err(FsError::InvalidArgument));
+        assert_eq!(reply(&mut fs, &["mkdir", "/dir3"]), Ok(Vec::new()));
+
+        // a status no error has is still an error.
+        let mut trans = Trans::new();
+        trans.set_status(200);
+        assert_eq!(
+            reply_result(&mut trans),
+            Err(FsError::Io(io::ErrorKind::InvalidData))
+        );
+    }
+
+    #[test]
+    fn test_handle_batch() {
+        let mut fs = Fs::new();
+        let batch = |cmds: &[&[&str]]| Batch::new(cmds.iter().map(|args| command(args)).collect());
+        let resps = |batch: &mut Batch| -> Vec<Result<Vec<u8>, FsError>> {
+            batch.trans.iter_mut().map(reply_result).collect()
+        };
+
+        // the batch makes it over the wire and back.
+        let mut b = Batch::decode(&batch(&[&["mkdir", "/a"], &["ls", "/"]]).encode()).unwrap();
+        assert_eq!(handle_batch(&mut fs, &mut b, OnError::Stop), 2);
+        let mut b = Batch::decode(&b.encode()).unwrap();
+        assert_eq!(resps(&mut b), vec![Ok(vec![]), Ok(b"a/\n".to_vec())]);
+
+        // later commands see the cwd set by earlier ones.
+        let mut b = batch(&[&["cd", "/a"], &["mkdir", "b"], &["ls", "/a"]]);
+        assert_eq!(handle_batch(&mut fs, &mut b, OnError::Stop), 3);
+        assert_eq!(resps(&mut b)[2], Ok(b"b/\n".to_vec()));
+
+        let cmds: &[&[&str]] = &[&["mkdir", "/a"], &["mkdir", "/c"]];
+        let mut b = batch(cmds);
+        assert_eq!(handle_batch(&mut fs, &mut b, OnError::Stop), 1);
+        assert_eq!(resps(&mut b)[0], Err(FsError::AlreadyExists));
+        assert!(b.trans[1].arg_mode());
+        assert_eq!(b.trans[1].num_args(), 2);
+        assert!(fs.walk(split_path("/c")).is_err());
+
+        let mut b = batch(cmds);
+        assert_eq!(handle_batch(&mut fs, &mut b, OnError::Continue), 2);
+        assert_eq!(resps(&mut b), vec![Err(FsError::AlreadyExists), Ok(vec![])]);
+        assert!(fs.walk(split_path("/c")).is_ok());
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut fs = demo_fs();
+        let before = fs.snapshot();
+        let mkdir = command(&["mkdir", "/dir1"]);
+        assert_eq!(validate(&fs, &mkdir), Err(FsError::AlreadyExists));
+        assert_eq!(mkdir.num_args(), 2);
+        assert_eq!(validate(&fs, &command(&["mkdir", "/dir1/new"])), Ok(()));
+        assert_eq!(fs.snapshot(), before);
+
+        let cases = [
+            (vec!["ls", "/dir1"], Ok(())),
+            (vec!["cat", "/dir1/f1"], Ok(())),
+            (vec!["rm", "/dir1/f2"], Ok(())),
+            (vec!["pwd"], Ok(())),
+            (vec!["cat", "/dir1"], Err(FsError::IsADirectory)),
+            (vec!["cd", "/dir1/f1"], Err(FsError::NotADirectory)),
+            (vec!["mkdir", "/nope/new"], Err(FsError::NotFound)),
+            (vec!["mkdir", "/dir1/f1/new"], Err(FsError::NotADirectory)),
+            (vec!["rm", "/dir1/gone"], Err(FsError::NotFound)),
+            (vec!["rm", "/dir2"], Err(FsError::IsADirectory)),
+            (vec!["ls"], Err(FsError::InvalidArgument)),
+            (vec!["bogus"], Err(FsError::InvalidArgument)),
+            (vec![], Err(FsError::InvalidArgument)),
+        ];
+        for (args, want) in cases {
+            assert_eq!(validate(&fs, &command(&args)), want, "{args:?}");
+        }
+        let long = format!("/dir1/{}", "x".repeat(300));
+        assert_eq!(
+            validate(&fs, &command(&["mkdir", &long])),
+            Err(FsError::NameTooLong)
+        );
+
+        // validating agrees with what running it does.
+        let mut session = Session::new(&fs);
+        let mut cd = command(&["cd", "dir1"]);
+        session.handle(&mut fs, &mut cd);
+        assert_eq!(session.validate(&fs, &command(&["cat", "f1"])), Ok(()));
+
+        // a read-only fs fails where handle does: after the path resolves,
+        // and for mkdir after the name is found free.
+        fs.set_read_only(true);
+        let cases = [
+            (vec!["mkdir", "/dir1/new"], Err(FsError::ReadOnly)),
+            (vec!["mkdir", "/dir1"], Err(FsError::AlreadyExists)),
+            (vec!["mkdir", "/nope/new"], Err(FsError::NotFound)),
+            (vec!["rm", "/dir1/f1"], Err(FsError::ReadOnly)),
+            (vec!["rm", "/dir1/gone"], Err(FsError::ReadOnly)),
+            (vec!["rm", "/nope/f"], Err(FsError::NotFound)),
+            (vec!["ls", "/dir1"], Ok(())),
+        ];
+        for (args, want) in cases {
+            assert_eq!(validate(&fs, &command(&args)), want, "{args:?}");
+            let mut trans = command(&args);
+            handle(&mut fs, &mut trans);
+            assert_eq!(reply_result(&mut trans).map(drop), want, "{args:?}");
+        }
+
+        // so do the tree's limits.
+        let config = FsConfig {
+            max_nodes: 1,
+            ..FsConfig::default()
+        };
+        let fs = Fs::with_config(config);
+        assert_eq!(
+            validate(&fs, &command(&["mkdir", "/d"])),
+            Err(FsError::NoSpace)
+        );
+    }
+
+    #[test]
+    fn test_handle_errors() {
+        let mut fs = demo_fs();
+        assert_eq!(call(&mut fs, &["cat", "/bogus"]), "error: not found\n");
+        assert_eq!(call(&mut fs, &["cat", "/dir1"]), "error: is a directory\n");
+        assert_eq!(
+            call(&mut fs, &["mkdir", "/dir1"]),
+            "error: already exists\n"
+        );
+        assert_eq!(call(&mut fs, &["rm", "/dir2"]), "error: is a directory\n");
+        assert_eq!(call(&mut fs, &["cat"]), "error: usage: cat <path>\n");
+        assert_eq!(call(&mut fs, &["bogus"]), "error: unknown command bogus\n");
+        assert_eq!(call(&mut fs, &[]), "error: no command\n");
+    }
+}
@@ -1,46 +1,336 @@
 use fuser::{FileAttr, FileType};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::fmt;
-use std::sync::{Arc, Mutex};
+use std::io;
+use std::sync::{mpsc, Arc, RwLock, Weak};
 use std::time;
 
+mod backend;
+mod fuse;
+mod handle;
+mod host;
+mod nfc;
+mod snapshot;
+
+pub use backend::{Backend, MemoryBackend};
+pub use fuse::Invalidation;
+use handle::OpenState;
+
+// defaults for FsConfig.
 const OWNER_UID: u32 = 0;
 const OWNER_GID: u32 = 55;
 const DIR_PERM: u16 = 0o550;
 const FILE_PERM: u16 = 0o440;
+const MAX_NAME_LEN: usize = 255;
+const ATTR_TTL: time::Duration = time::Duration::from_secs(1);
+
+const SYMLINK_PERM: u16 = 0o777;
+const PERM_MASK: u16 = 0o1777; // perm bits chmod may set
+const STICKY: u16 = 0o1000; // in a dir's perm, only owners may delete
+
+// how many symlinks a walk may follow before giving up.
+pub const MAX_SYMLINK_HOPS: usize = 40;
+
+// default for how many components a walk may resolve before giving up.
+pub const MAX_WALK_DEPTH: usize = 256;
+
+// default for how many bytes of file data may be stored.
+pub const DEFAULT_CAPACITY: u64 = 1 << 30;
+
+// largest xattr value that may be stored, matching linux's XATTR_SIZE_MAX.
+pub const MAX_XATTR_SIZE: usize = 64 * 1024;
+
+pub type Xattrs = HashMap<String, Vec<u8>>;
 
-fn split_path(path: &str) -> Vec<String> {
+// split_path breaks path into its components for walk.
+pub fn split_path(path: &str) -> Vec<String> {
     path.split('/')
-        .filter(|comp| comp.len() > 0)
+        .filter(|comp| !comp.is_empty())
         .map(|comp| comp.to_owned())
         .collect()
 }
 
-fn new_attr(ino: u64, kind: FileType, perm: u16, nlink: u32) -> FileAttr {
+// join_path puts comps back together as an absolute path. empty and "."
+// comps are dropped, and ".." is kept as it is.
+pub fn join_path(comps: &[String]) -> String {
+    let comps: Vec<&str> = comps
+        .iter()
+        .map(String::as_str)
+        .filter(|comp| !comp.is_empty() && *comp != ".")
+        .collect();
+    format!("/{}", comps.join("/"))
+}
+
+// canonicalize resolves "." and ".." in path by its text alone, without
+// looking at the tree or following symlinks. ".." at the root stays there.
+pub fn canonicalize(path: &str) -> String {
+    let mut comps = Vec::new();
+    for comp in split_path(path) {
+        match comp.as_str() {
+            "." => {}
+            ".." => {
+                comps.pop();
+            }
+            _ => comps.push(comp),
+        }
+    }
+    join_path(&comps)
+}
+
+// validate_name checks that name can be used as a directory entry.
+// names longer than max_len bytes are refused.
+fn validate_name(name: &str, max_len: usize) -> Result<(), FsError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+        return Err(FsError::NameInvalid);
+    }
+    if name.len() > max_len {
+        return Err(FsError::NameTooLong);
+    }
+    Ok(())
+}
+
+// FsConfig holds the ownership and permissions given to new nodes,
+// how names are matched, and limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsConfig {
+    pub uid: u32,
+    pub gid: u32,
+    pub dir_perm: u16,
+    pub file_perm: u16,
+    pub read_only: bool,        // refuse all changes with ReadOnly
+    pub case_insensitive: bool, // match names after lowercasing
+    pub normalize_nfc: bool,    // store and match names in NFC
+    pub max_name_len: usize,    // longest name allowed, in bytes
+    pub capacity_bytes: u64,    // most file data that may be stored
+    pub max_nodes: u64,         // most nodes the tree may hold, the root included
+    pub max_depth: usize,       // deepest a node may sit, the root's kids being at 1
+
+    // how long the kernel may cache attrs and entries from FUSE replies. a
+    // longer ttl saves round trips, but changes made through the network
+    // protocol go unseen by the mount until it runs out. zero turns caching
+    // off, so every access asks us again.
+    pub attr_ttl: time::Duration,
+
+    // how long the kernel may remember that a lookup found nothing. a name
+    // created while the miss is cached is dropped from the cache by the
+    // entry invalidation the mount sends for its Created event. zero, the
+    // default, leaves misses uncached.
+    pub negative_ttl: time::Duration,
+}
+
+impl Default for FsConfig {
+    fn default() -> Self {
+        FsConfig {
+            uid: OWNER_UID,
+            gid: OWNER_GID,
+            dir_perm: DIR_PERM,
+            file_perm: FILE_PERM,
+            read_only: false,
+            case_insensitive: false,
+            normalize_nfc: false,
+            max_name_len: MAX_NAME_LEN,
+            capacity_bytes: DEFAULT_CAPACITY,
+            max_nodes: u64::MAX,
+            max_depth: usize::MAX,
+            attr_ttl: ATTR_TTL,
+            negative_ttl: time::Duration::ZERO,
+        }
+    }
+}
+
+fn new_attr(config: &FsConfig, ino: u64, kind: FileType, nlink: u32) -> FileAttr {
     let now = time::SystemTime::now();
+    let perm = match kind {
+        FileType::Directory => config.dir_perm,
+        FileType::Symlink => SYMLINK_PERM,
+        _ => config.file_perm,
+    };
     FileAttr {
-        ino: ino,
+        ino,
         atime: now,
         mtime: now,
         ctime: now,
         crtime: now,
-        kind: kind,
-        perm: perm,
-        nlink: nlink,
-        uid: OWNER_UID,
-        gid: OWNER_GID,
+        kind,
+        perm,
+        nlink,
+        uid: config.uid,
+        gid: config.gid,
         blksize: 512,
 
         size: 0,
         blocks: 0,
         rdev: 0,
         flags: 0,
-        padding: 0,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    NotEmpty,
+    NameInvalid,
+    NameTooLong,
+    InvalidArgument,
+    TooManyLinks,
+    PathTooLong,
+    NoXattr,
+    XattrTooBig,
+    NotSupported,
+    ReadOnly,
+    NotASymlink,
+    Io(io::ErrorKind),
+    BadSnapshot,
+    SnapshotVersion(u32),
+    BadHandle,
+    NoSpace,
+    PermissionDenied,
+    TooDeep,
+}
+
+impl FsError {
+    // code gives the nonzero status byte that stands for this error in a
+    // response. see from_code.
+    pub fn code(&self) -> u8 {
+        match self {
+            FsError::NotFound => 1,
+            FsError::NotADirectory => 2,
+            FsError::IsADirectory => 3,
+            FsError::AlreadyExists => 4,
+            FsError::NotEmpty => 5,
+            FsError::NameInvalid => 6,
+            FsError::NameTooLong => 7,
+            FsError::InvalidArgument => 8,
+            FsError::TooManyLinks => 9,
+            FsError::PathTooLong => 10,
+            FsError::NoXattr => 11,
+            FsError::XattrTooBig => 12,
+            FsError::NotSupported => 13,
+            FsError::ReadOnly => 14,
+            FsError::NotASymlink => 15,
+            FsError::Io(_) => 16,
+            FsError::BadSnapshot => 17,
+            FsError::SnapshotVersion(_) => 18,
+            FsError::BadHandle => 19,
+            FsError::NoSpace => 20,
+            FsError::PermissionDenied => 21,
+            FsError::TooDeep => 22,
+        }
+    }
+
+    // from_code gives back the error for a status byte made by code. the
+    // io::ErrorKind or version carried by some errors doesn't survive.
+    pub fn from_code(code: u8) -> Option<FsError> {
+        let e = match code {
+            1 => FsError::NotFound,
+            2 => FsError::NotADirectory,
+            3 => FsError::IsADirectory,
+            4 => FsError::AlreadyExists,
+            5 => FsError::NotEmpty,
+            6 => FsError::NameInvalid,
+            7 => FsError::NameTooLong,
+            8 => FsError::InvalidArgument,
+            9 => FsError::TooManyLinks,
+            10 => FsError::PathTooLong,
+            11 => FsError::NoXattr,
+            12 => FsError::XattrTooBig,
+            13 => FsError::NotSupported,
+            14 => FsError::ReadOnly,
+            15 => FsError::NotASymlink,
+            16 => FsError::Io(io::ErrorKind::Other),
+            17 => FsError::BadSnapshot,
+            18 => FsError::SnapshotVersion(0),
+            19 => FsError::BadHandle,
+            20 => FsError::NoSpace,
+            21 => FsError::PermissionDenied,
+            22 => FsError::TooDeep,
+            _ => return None,
+        };
+        Some(e)
+    }
+
+    // errno gives the libc error code to report for this error.
+    pub fn errno(&self) -> i32 {
+        match self {
+            FsError::NotFound => libc::ENOENT,
+            FsError::NotADirectory => libc::ENOTDIR,
+            FsError::IsADirectory => libc::EISDIR,
+            FsError::AlreadyExists => libc::EEXIST,
+            FsError::NotEmpty => libc::ENOTEMPTY,
+            FsError::NameInvalid => libc::EINVAL,
+            FsError::NameTooLong => libc::ENAMETOOLONG,
+            FsError::InvalidArgument => libc::EINVAL,
+            FsError::TooManyLinks => libc::ELOOP,
+            FsError::PathTooLong => libc::ENAMETOOLONG,
+            FsError::NoXattr => libc::ENODATA,
+            FsError::XattrTooBig => libc::E2BIG,
+            FsError::NotSupported => libc::ENOTSUP,
+            FsError::ReadOnly => libc::EROFS,
+            FsError::NotASymlink => libc::EINVAL,
+            FsError::Io(_) => libc::EIO,
+            FsError::BadSnapshot => libc::EINVAL,
+            FsError::SnapshotVersion(_) => libc::EINVAL,
+            FsError::BadHandle => libc::EBADF,
+            FsError::NoSpace => libc::ENOSPC,
+            FsError::PermissionDenied => libc::EACCES,
+            FsError::TooDeep => libc::ENAMETOOLONG,
+        }
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            FsError::NotFound => "not found",
+            FsError::NotADirectory => "not a directory",
+            FsError::IsADirectory => "is a directory",
+            FsError::AlreadyExists => "already exists",
+            FsError::NotEmpty => "directory not empty",
+            FsError::NameInvalid => "invalid name",
+            FsError::NameTooLong => "name too long",
+            FsError::InvalidArgument => "invalid argument",
+            FsError::TooManyLinks => "too many levels of symbolic links",
+            FsError::PathTooLong => "path too long",
+            FsError::NoXattr => "no such attribute",
+            FsError::XattrTooBig => "attribute too big",
+            FsError::NotSupported => "operation not supported",
+            FsError::ReadOnly => "read-only file system",
+            FsError::NotASymlink => "not a symlink",
+            FsError::Io(kind) => return write!(f, "i/o error: {}", kind),
+            FsError::BadSnapshot => "corrupt snapshot",
+            FsError::SnapshotVersion(v) => {
+                return write!(f, "unsupported snapshot version {}", v);
+            }
+            FsError::BadHandle => "bad file handle",
+            FsError::NoSpace => "no space left on device",
+            FsError::PermissionDenied => "permission denied",
+            FsError::TooDeep => "tree too deep",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+impl std::error::Error for FsError {}
+
+impl From<FsError> for io::Error {
+    fn from(e: FsError) -> Self {
+        match e {
+            FsError::Io(kind) => io::Error::from(kind),
+            e => io::Error::from_raw_os_error(e.errno()),
+        }
     }
 }
 
 pub trait Elem {
     fn get_attr(&self) -> &FileAttr;
+    fn get_mut_attr(&mut self) -> &mut FileAttr;
+    fn get_parent(&self) -> Option<Kid>;
+    fn set_parent(&mut self, parent: WeakKid);
     fn to_dir(&self) -> Option<&Dir> {
         None
     }
@@ -50,29 +340,65 @@ pub trait Elem {
     fn to_file(&self) -> Option<&File> {
         None
     }
+    fn to_mut_file(&mut self) -> Option<&mut File> {
+        None
+    }
+    fn to_symlink(&self) -> Option<&Symlink> {
+        None
+    }
+    fn to_mut_symlink(&mut self) -> Option<&mut Symlink> {
+        None
+    }
+    fn to_special(&self) -> Option<&Special> {
+        None
+    }
+    fn get_xattrs(&self) -> Option<&Xattrs> {
+        None
+    }
+    fn get_mut_xattrs(&mut self) -> Option<&mut Xattrs> {
+        None
+    }
 }
 
 // we want a bunch of traits. wrap em up.
-pub trait DispElem: fmt::Debug + fmt::Display + Elem {}
-impl<T> DispElem for T where T: fmt::Debug + fmt::Display + Elem {}
-pub type Kid = Arc<Mutex<Box<dyn DispElem>>>;
+pub trait DispElem: fmt::Debug + fmt::Display + Elem + Send + Sync {}
+impl<T> DispElem for T where T: fmt::Debug + fmt::Display + Elem + Send + Sync {}
+pub type Kid = Arc<RwLock<Box<dyn DispElem>>>;
+pub type WeakKid = Weak<RwLock<Box<dyn DispElem>>>;
 
 #[derive(Debug)]
 pub struct Dir {
     attr: FileAttr,
-    kids: HashMap<String, Kid>, // strictly tree, no "." or ".."
+    parent: WeakKid,             // dangling for the root and detached nodes
+    kids: BTreeMap<String, Kid>, // strictly tree, no "." or "..", in name order
+    xattrs: Xattrs,
 }
 
 impl Elem for Dir {
     fn get_attr(&self) -> &FileAttr {
         &self.attr
     }
+    fn get_mut_attr(&mut self) -> &mut FileAttr {
+        &mut self.attr
+    }
+    fn get_parent(&self) -> Option<Kid> {
+        self.parent.upgrade()
+    }
+    fn set_parent(&mut self, parent: WeakKid) {
+        self.parent = parent;
+    }
     fn to_dir(&self) -> Option<&Dir> {
         Some(self)
     }
     fn to_mut_dir(&mut self) -> Option<&mut Dir> {
         Some(self)
     }
+    fn get_xattrs(&self) -> Option<&Xattrs> {
+        Some(&self.xattrs)
+    }
+    fn get_mut_xattrs(&mut self) -> Option<&mut Xattrs> {
+        Some(&mut self.xattrs)
+    }
 }
 
 impl fmt::Display for Dir {
@@ -83,216 +409,3674 @@ impl fmt::Display for Dir {
 }
 
 impl Dir {
-    fn new(ino: u64) -> Self {
+    fn new(config: &FsConfig, ino: u64) -> Self {
         Dir {
-            attr: new_attr(ino, FileType::Directory, DIR_PERM, 2),
-            kids: HashMap::new(),
+            attr: new_attr(config, ino, FileType::Directory, 2),
+            parent: Weak::new(),
+            kids: BTreeMap::new(),
+            xattrs: Xattrs::new(),
         }
     }
 
-    fn to_kid(self) -> Kid {
-        Arc::new(Mutex::new(Box::new(self)))
+    fn into_kid(self) -> Kid {
+        Arc::new(RwLock::new(Box::new(self)))
     }
 }
 
 #[derive(Debug)]
 pub struct File {
     attr: FileAttr,
-    data: String,
+    parent: WeakKid,
+    data: Vec<u8>,
+    xattrs: Xattrs,
 }
 
 impl Elem for File {
     fn get_attr(&self) -> &FileAttr {
         &self.attr
     }
+    fn get_mut_attr(&mut self) -> &mut FileAttr {
+        &mut self.attr
+    }
+    fn get_parent(&self) -> Option<Kid> {
+        self.parent.upgrade()
+    }
+    fn set_parent(&mut self, parent: WeakKid) {
+        self.parent = parent;
+    }
     fn to_file(&self) -> Option<&File> {
         Some(self)
     }
+    fn to_mut_file(&mut self) -> Option<&mut File> {
+        Some(self)
+    }
+    fn get_xattrs(&self) -> Option<&Xattrs> {
+        Some(&self.xattrs)
+    }
+    fn get_mut_xattrs(&mut self) -> Option<&mut Xattrs> {
+        Some(&mut self.xattrs)
+    }
 }
 
 impl fmt::Display for File {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "File({})", self.data)
+        write!(f, "File({})", String::from_utf8_lossy(&self.data))
     }
 }
 
 impl File {
-    fn new(ino: u64, dat: &str) -> Self {
-        File {
-            attr: new_attr(ino, FileType::RegularFile, FILE_PERM, 1),
-            data: dat.to_owned(),
+    fn new(config: &FsConfig, ino: u64, dat: &[u8]) -> Self {
+        let mut file = File {
+            attr: new_attr(config, ino, FileType::RegularFile, 1),
+            parent: Weak::new(),
+            data: dat.to_vec(),
+            xattrs: Xattrs::new(),
+        };
+        file.update_size();
+        file
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    // truncate resizes data to len, zero filling if it grows.
+    fn truncate(&mut self, len: usize) {
+        self.data.resize(len, 0);
+        self.update_size();
+        self.attr.mtime = time::SystemTime::now();
+    }
+
+    // write overwrites data at offset, extending the file as needed.
+    // end is where the write stops, as write_end gives it.
+    fn write(&mut self, offset: usize, end: usize, dat: &[u8]) {
+        if end > self.data.len() {
+            self.data.resize(end, 0);
+        }
+        self.data[offset..end].copy_from_slice(dat);
+        self.update_size();
+        self.attr.mtime = time::SystemTime::now();
+    }
+
+    // update_size syncs the size attributes with data. call after data changes.
+    fn update_size(&mut self) {
+        let size = self.data.len() as u64;
+        let blksize = self.attr.blksize as u64;
+        self.attr.size = size;
+        self.attr.blocks = size.div_ceil(blksize);
+    }
+
+    fn into_kid(self) -> Kid {
+        Arc::new(RwLock::new(Box::new(self)))
+    }
+}
+
+#[derive(Debug)]
+pub struct Symlink {
+    attr: FileAttr,
+    parent: WeakKid,
+    target: String,
+}
+
+impl Elem for Symlink {
+    fn get_attr(&self) -> &FileAttr {
+        &self.attr
+    }
+    fn get_mut_attr(&mut self) -> &mut FileAttr {
+        &mut self.attr
+    }
+    fn get_parent(&self) -> Option<Kid> {
+        self.parent.upgrade()
+    }
+    fn set_parent(&mut self, parent: WeakKid) {
+        self.parent = parent;
+    }
+    fn to_symlink(&self) -> Option<&Symlink> {
+        Some(self)
+    }
+    fn to_mut_symlink(&mut self) -> Option<&mut Symlink> {
+        Some(self)
+    }
+}
+
+impl fmt::Display for Symlink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Symlink(-> {})", self.target)
+    }
+}
+
+impl Symlink {
+    fn new(config: &FsConfig, ino: u64, target: &str) -> Self {
+        let mut attr = new_attr(config, ino, FileType::Symlink, 1);
+        attr.size = target.len() as u64;
+        Symlink {
+            attr,
+            parent: Weak::new(),
+            target: target.to_owned(),
+        }
+    }
+
+    fn into_kid(self) -> Kid {
+        Arc::new(RwLock::new(Box::new(self)))
+    }
+}
+
+// Special is a node with no contents of its own, like a named pipe, socket
+// or device. its attr.kind says which, and attr.rdev a device's number.
+#[derive(Debug)]
+pub struct Special {
+    attr: FileAttr,
+    parent: WeakKid,
+    xattrs: Xattrs,
+}
+
+impl Elem for Special {
+    fn get_attr(&self) -> &FileAttr {
+        &self.attr
+    }
+    fn get_mut_attr(&mut self) -> &mut FileAttr {
+        &mut self.attr
+    }
+    fn get_parent(&self) -> Option<Kid> {
+        self.parent.upgrade()
+    }
+    fn set_parent(&mut self, parent: WeakKid) {
+        self.parent = parent;
+    }
+    fn to_special(&self) -> Option<&Special> {
+        Some(self)
+    }
+    fn get_xattrs(&self) -> Option<&Xattrs> {
+        Some(&self.xattrs)
+    }
+    fn get_mut_xattrs(&mut self) -> Option<&mut Xattrs> {
+        Some(&mut self.xattrs)
+    }
+}
+
+impl fmt::Display for Special {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Special({:?})", self.attr.kind)
+    }
+}
+
+impl Special {
+    fn new(config: &FsConfig, ino: u64, kind: FileType, rdev: u32) -> Self {
+        let mut attr = new_attr(config, ino, kind, 1);
+        attr.rdev = rdev;
+        Special {
+            attr,
+            parent: Weak::new(),
+            xattrs: Xattrs::new(),
         }
     }
 
-    fn to_kid(self) -> Kid {
-        Arc::new(Mutex::new(Box::new(self)))
+    fn into_kid(self) -> Kid {
+        Arc::new(RwLock::new(Box::new(self)))
     }
 }
 
+// FsStat is a summary of filesystem usage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsStat {
+    pub inodes: u64,   // live nodes
+    pub bytes: u64,    // file data stored
+    pub capacity: u64, // file data that may be stored
+}
+
+// DiffEntry is a difference found by diff, at a path relative to the trees compared.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffEntry {
+    OnlyInA(String),
+    OnlyInB(String),
+    Type(String),     // say a dir in one and a file in the other
+    Contents(String), // file data or symlink target
+    Perm(String),
+}
+
+// FsEvent describes a change to the tree. see Fs::subscribe. each name
+// comes with the inode of the dir holding it, so an event can be acted on
+// without the Fs. Removed is sent for every name taken out of a dir; the
+// node itself is only gone once it has no names left.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsEvent {
+    Created {
+        ino: u64,
+        parent: u64,
+        path: String,
+    },
+    Removed {
+        ino: u64,
+        parent: u64,
+        name: String,
+    },
+    Modified {
+        ino: u64,
+    },
+    Renamed {
+        from_parent: u64,
+        from: String,
+        to_parent: u64,
+        to: String,
+    },
+}
+
 #[derive(Debug)]
 pub struct Fs {
     inode_alloc: u64,
     root: Kid,
+    inodes: HashMap<u64, Kid>,      // every live node by inode number
+    free_inodes: Vec<u64>,          // released inode numbers to reuse
+    generations: HashMap<u64, u64>, // times each inode number has been reused
+    max_depth: usize,               // most components a walk will resolve
+    used_bytes: u64,                // sum of all file sizes
+    config: FsConfig,
+    events: Option<mpsc::Sender<FsEvent>>,
+    handle_alloc: u64,
+    handles: HashMap<u64, OpenState>, // open files by handle
+    free_handles: Vec<u64>,
+    backend: Box<dyn Backend>,
+}
+
+impl Default for Fs {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Fs {
     pub fn new() -> Self {
+        Self::with_config(FsConfig::default())
+    }
+
+    pub fn with_config(config: FsConfig) -> Self {
+        let root = Dir::new(&config, 1).into_kid();
         Fs {
             inode_alloc: 1,
-            root: Dir::new(1).to_kid(),
+            root: root.clone(),
+            inodes: HashMap::from([(1, root)]),
+            free_inodes: Vec::new(),
+            generations: HashMap::new(),
+            max_depth: MAX_WALK_DEPTH,
+            used_bytes: 0,
+            config,
+            events: None,
+            handle_alloc: 0,
+            handles: HashMap::new(),
+            free_handles: Vec::new(),
+            backend: Box::new(MemoryBackend),
+        }
+    }
+
+    // set_max_depth limits how many components, including those from
+    // expanded symlinks, a single walk will resolve.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    // set_capacity limits how many bytes of file data may be stored. lowering
+    // it below what is already stored only stops files from growing.
+    pub fn set_capacity(&mut self, capacity: u64) {
+        self.config.capacity_bytes = capacity;
+    }
+
+    // set_read_only freezes the tree, or thaws it. a tree can be populated
+    // and then frozen for serving.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.config.read_only = read_only;
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.config.read_only
+    }
+
+    fn check_writable(&self) -> Result<(), FsError> {
+        if self.config.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    // check_space refuses to grow a file from old_size to new_size bytes
+    // if that would store more than the capacity.
+    fn check_space(&self, old_size: u64, new_size: u64) -> Result<(), FsError> {
+        if new_size > old_size && self.used_bytes - old_size + new_size > self.config.capacity_bytes
+        {
+            return Err(FsError::NoSpace);
+        }
+        Ok(())
+    }
+
+    // check_limits refuses to add a node under parent if the tree already
+    // holds max_nodes, or if the node would sit deeper than max_depth.
+    fn check_limits(&self, parent: &Kid) -> Result<(), FsError> {
+        if self.inodes.len() as u64 >= self.config.max_nodes {
+            return Err(FsError::NoSpace);
+        }
+        let mut depth = 1;
+        let mut cur = parent.read().unwrap().get_parent();
+        loop {
+            if depth > self.config.max_depth {
+                return Err(FsError::TooDeep);
+            }
+            let Some(dir) = cur else {
+                return Ok(());
+            };
+            depth += 1;
+            cur = dir.read().unwrap().get_parent();
+        }
+    }
+
+    pub fn statfs(&self) -> FsStat {
+        FsStat {
+            inodes: self.inodes.len() as u64,
+            bytes: self.used_bytes,
+            capacity: self.config.capacity_bytes,
         }
     }
 
     fn alloc_inode(&mut self) -> u64 {
+        if let Some(ino) = self.free_inodes.pop() {
+            *self.generations.entry(ino).or_default() += 1;
+            return ino;
+        }
         self.inode_alloc += 1;
         self.inode_alloc
     }
 
+    // generation tells apart the nodes that have had inode number ino. it
+    // goes up each time the number is reused, so FUSE can spot stale handles.
+    pub fn generation(&self, ino: u64) -> u64 {
+        self.generations.get(&ino).copied().unwrap_or(0)
+    }
+
+    fn release_inode(&mut self, ino: u64) {
+        self.inodes.remove(&ino);
+        self.free_inodes.push(ino);
+    }
+
     pub fn root(&self) -> Kid {
         self.root.clone()
     }
 
-    pub fn new_file(&mut self, parent: Kid, name: &str, dat: &str) -> Option<Kid> {
-        let mut locked = parent.lock().unwrap();
-        let dir = locked.to_mut_dir()?;
-        let kid = File::new(self.alloc_inode(), dat).to_kid();
-        dir.kids.insert(name.to_owned(), kid.clone());
-        Some(kid)
+    // get finds a node by inode number.
+    pub fn get(&self, ino: u64) -> Option<Kid> {
+        self.inodes.get(&ino).cloned()
     }
 
-    pub fn new_dir(&mut self, parent: Kid, name: &str) -> Option<Kid> {
-        let mut locked = parent.lock().unwrap();
-        let dir = locked.to_mut_dir()?;
-        let kid = Dir::new(self.alloc_inode()).to_kid();
-        dir.kids.insert(name.to_owned(), kid.clone());
-        Some(kid)
+    // norm_name gives name the way it is stored, which is in NFC with normalize_nfc set.
+    fn norm_name<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        if self.config.normalize_nfc {
+            nfc::to_nfc(name)
+        } else {
+            Cow::Borrowed(name)
+        }
     }
 
-    pub fn walk(&mut self, comps: Vec<String>) -> Option<Kid> {
-        // println!("walking {comps:?}");
-        let mut parents: Vec<Kid> = Vec::new();
-        let mut cur = self.root.clone();
-        for comp in comps {
-            //println!("comp {comp} current {}", cur.lock().unwrap());
-            if comp.len() == 0 {
-                continue;
-            }
+    // find_kid gives the stored name and kid of dir's entry matching name.
+    // with case_insensitive set, names match after lowercasing.
+    fn find_kid<'a>(&self, dir: &'a Dir, name: &str) -> Option<(&'a String, &'a Kid)> {
+        let name = &*self.norm_name(name);
+        if let Some(entry) = dir.kids.get_key_value(name) {
+            return Some(entry);
+        }
+        if !self.config.case_insensitive {
+            return None;
+        }
+        let folded = name.to_lowercase();
+        dir.kids
+            .iter()
+            .find(|(key, _)| key.to_lowercase() == folded)
+    }
 
-            let mut next = None;
-            let mut add_parent = false;
+    // check_collision refuses a new name already taken in dir, or taken under
+    // another case when the fs is case insensitive.
+    fn check_collision(&self, dir: &Dir, name: &str) -> Result<(), FsError> {
+        match self.find_kid(dir, name) {
+            Some(_) => Err(FsError::AlreadyExists),
+            None => Ok(()),
+        }
+    }
 
-            // find out what's next under lock.
-            if let Some(dir) = cur.lock().unwrap().to_dir() {
-                if comp == "." {
-                    // keep cur...
-                } else if comp == ".." {
-                    if let Some(parent) = parents.pop() {
-                        next = Some(parent.clone());
-                    }
-                } else if let Some(kid) = dir.kids.get(&comp) {
-                    add_parent = true;
-                    next = Some(kid.clone());
-                } else {
-                    //println!("not found");
-                    return None;
-                }
-            } else {
-                //println!("cur not dir");
-                return None;
-            }
+    // check_create says whether a new entry named name could be made in
+    // parent, failing as new_file and new_dir would without making anything.
+    pub fn check_create(&self, parent: &Kid, name: &str) -> Result<(), FsError> {
+        self.check_writable()?;
+        let name = &*self.norm_name(name);
+        validate_name(name, self.config.max_name_len)?;
+        self.check_limits(parent)?;
+        let locked = parent.read().unwrap();
+        let dir = locked.to_dir().ok_or(FsError::NotADirectory)?;
+        self.check_collision(dir, name)
+    }
 
-            // move to next
-            if add_parent {
-                parents.push(cur.clone());
-            }
-            if let Some(next) = next {
-                cur = next;
-            }
-        }
-        //println!("found {}", cur.lock().unwrap());
-        return Some(cur);
+    // getattr gives a copy of kid's attrs, so no lock is held afterwards.
+    pub fn getattr(&self, kid: &Kid) -> FileAttr {
+        *kid.read().unwrap().get_attr()
     }
 
-    pub fn test_walk(&mut self, path: &str) -> Option<Kid> {
-        let comps = split_path(path);
-        println!("walking {path} {comps:?}");
-        let r = self.walk(comps);
-        if let Some(ref kid) = r {
-            println!("got {}", kid.lock().unwrap());
+    // access says whether a caller with uid and gid may act on kid as mask
+    // asks. see permits.
+    pub fn access(&self, kid: &Kid, uid: u32, gid: u32, mask: u16) -> bool {
+        permits(&self.getattr(kid), uid, gid, mask)
+    }
+
+    // forget drops a node that was unlinked from the tree.
+    fn forget(&mut self, kid: &Kid) {
+        let mut locked = kid.write().unwrap();
+        locked.set_parent(Weak::new());
+        if let Some(file) = locked.to_file() {
+            self.used_bytes -= file.attr.size;
         }
-        println!("");
-        r
+        let ino = locked.get_attr().ino;
+        self.release_inode(ino);
     }
 
-    pub fn show_tree(&mut self) {
-        show_tree(self.root(), ".", 0);
+    // drop_link accounts for a non-directory losing its name under the dir
+    // with inode parent, forgetting it once the last one is gone.
+    fn drop_link(&mut self, kid: &Kid, parent: u64, name: &str) {
+        let mut locked = kid.write().unwrap();
+        let attr = locked.get_mut_attr();
+        attr.nlink -= 1;
+        attr.ctime = time::SystemTime::now();
+        let (ino, gone) = (attr.ino, attr.nlink == 0);
+        let old_parent = locked.get_parent();
+        drop(locked);
+        let holds = |dir: &Kid| {
+            let locked = dir.read().unwrap();
+            let kids = locked.to_dir().map(|dir| dir.kids.values());
+            kids.is_some_and(|mut kids| kids.any(|k| Arc::ptr_eq(k, kid)))
+        };
+        if gone {
+            self.forget(kid);
+        } else if old_parent.is_some_and(|dir| kid_ino(&dir) == parent && !holds(&dir)) {
+            // move the parent link to a dir still holding one of its names,
+            // or path_of would lose track of it.
+            let dir = self.inodes.values().find(|dir| holds(dir));
+            let parent = dir.map(Arc::downgrade).unwrap_or_default();
+            kid.write().unwrap().set_parent(parent);
+        }
+        self.notify_removed(ino, parent, name);
     }
-}
 
-pub fn show_tree(k: Kid, name: &str, level: usize) {
-    if level == 0 {
-        println!("Tree:");
+    // subscribe starts sending an FsEvent for every change to the tree,
+    // replacing any earlier subscriber.
+    pub fn subscribe(&mut self) -> mpsc::Receiver<FsEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.events = Some(tx);
+        rx
     }
-    println!(
-        "{0:>1$}[{2}] {3}: {4}",
-        "",
-        level * 2,
-        Arc::strong_count(&k),
-        name,
-        k.lock().unwrap()
-    );
 
-    if let Some(dir) = k.lock().unwrap().to_dir() {
-        for (nm, kid) in dir.kids.iter() {
-            show_tree(kid.clone(), &nm, level + 1);
+    // notify sends event to the subscriber. call with no locks held.
+    fn notify(&mut self, event: FsEvent) {
+        let gone = match &self.events {
+            Some(tx) => tx.send(event).is_err(),
+            None => false,
+        };
+        if gone {
+            self.events = None;
         }
     }
 
-    if level == 0 {
-        println!("");
+    fn notify_renamed(
+        &mut self,
+        old_parent: &Kid,
+        old_name: &str,
+        new_parent: &Kid,
+        new_name: &str,
+    ) {
+        if self.events.is_none() {
+            return;
+        }
+        let from = self
+            .path_of(old_parent)
+            .map(|dir| join_name(&dir, old_name));
+        let to = self
+            .path_of(new_parent)
+            .map(|dir| join_name(&dir, new_name));
+        if let (Some(from), Some(to)) = (from, to) {
+            let (from_parent, to_parent) = (kid_ino(old_parent), kid_ino(new_parent));
+            self.notify(FsEvent::Renamed {
+                from_parent,
+                from,
+                to_parent,
+                to,
+            });
+        }
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::fs::*;
 
-    #[test]
-    fn test_walk() {
-        fn get_inode(f: Kid) -> u64 {
-            f.lock().unwrap().get_attr().ino
+    fn notify_removed(&mut self, ino: u64, parent: u64, name: &str) {
+        if self.events.is_some() {
+            let name = name.to_owned();
+            self.notify(FsEvent::Removed { ino, parent, name });
         }
-        fn oget_inode(of: Option<Kid>) -> Option<u64> {
-            of.map(get_inode)
+    }
+
+    fn notify_created(&mut self, kid: &Kid) {
+        if self.events.is_none() {
+            return;
         }
-        fn walk_gives(fs: &mut Fs, path: &str, want: Kid) {
-            let want_ino = get_inode(want);
-            let targ = fs.test_walk(path);
-            assert_eq!(oget_inode(targ), Some(want_ino));
+        let parent = kid.read().unwrap().get_parent();
+        if let (Some(parent), Some(path)) = (parent, self.path_of(kid)) {
+            let (ino, parent) = (kid_ino(kid), kid_ino(&parent));
+            self.notify(FsEvent::Created { ino, parent, path });
         }
-        fn walk_fails(fs: &mut Fs, path: &str) {
-            let targ = fs.test_walk(path);
-            assert_eq!(oget_inode(targ), None);
+    }
+
+    pub fn new_file(&mut self, parent: Kid, name: &str, dat: &[u8]) -> Result<Kid, FsError> {
+        let parent_ino = log_ino(&parent);
+        let res = self.new_file_inner(parent, name, dat);
+        if let Some(ino) = parent_ino {
+            log_op(format_args!("new_file {ino}/{name}"), &res);
         }
+        res
+    }
 
-        let mut fs = Fs::new();
-        let root = fs.root();
-        let d1 = fs.new_dir(root.clone(), "dir1").unwrap();
-        let d2 = fs.new_dir(fs.root(), "dir2").unwrap();
-        let f1 = fs.new_file(d1.clone(), "f1", "file1 here").unwrap();
-        let f2 = fs.new_file(d1.clone(), "f2", "file2 here").unwrap();
+    fn new_file_inner(&mut self, parent: Kid, name: &str, dat: &[u8]) -> Result<Kid, FsError> {
+        self.check_writable()?;
+        let name = &*self.norm_name(name);
+        validate_name(name, self.config.max_name_len)?;
+        self.check_space(0, dat.len() as u64)?;
+        self.check_limits(&parent)?;
+        let kid = with_dir(&parent, |dir| -> Result<Kid, FsError> {
+            self.check_collision(dir, name)?;
+            let ino = self.alloc_inode();
+            let mut file = File::new(&self.config, ino, dat);
+            file.parent = Arc::downgrade(&parent);
+            self.used_bytes += file.attr.size;
+            let kid = file.into_kid();
+            dir.kids.insert(name.to_owned(), kid.clone());
+            self.inodes.insert(ino, kid.clone());
+            Ok(kid)
+        })
+        .ok_or(FsError::NotADirectory)??;
+        self.notify_created(&kid);
+        Ok(kid)
+    }
 
-        assert_ne!(get_inode(f1.clone()), get_inode(f2.clone()));
-        assert_ne!(get_inode(d1.clone()), get_inode(f2.clone()));
+    // create_or_replace makes a file like new_file, first unlinking any
+    // non-directory already at name. the old node is freed once it has no
+    // other links.
+    pub fn create_or_replace(
+        &mut self,
+        parent: Kid,
+        name: &str,
+        dat: &[u8],
+    ) -> Result<Kid, FsError> {
+        match self.unlink(parent.clone(), name) {
+            Ok(_) | Err(FsError::NotFound) => self.new_file(parent, name, dat),
+            Err(e) => Err(e),
+        }
+    }
 
-        walk_gives(&mut fs, "/dir1/f1", f1.clone());
-        walk_gives(&mut fs, "dir1/f1", f1.clone());
-        walk_gives(&mut fs, "dir1/f2", f2.clone());
-        walk_gives(&mut fs, "/dir1/../dir2", d2.clone());
-        walk_gives(&mut fs, "//dir2/.././/dir1/f1", f1.clone());
-        walk_gives(&mut fs, "//dir2/.././/dir1/../../../../../..", root.clone());
+    pub fn new_dir(&mut self, parent: Kid, name: &str) -> Result<Kid, FsError> {
+        let parent_ino = log_ino(&parent);
+        let res = self.new_dir_inner(parent, name);
+        if let Some(ino) = parent_ino {
+            log_op(format_args!("new_dir {ino}/{name}"), &res);
+        }
+        res
+    }
+
+    fn new_dir_inner(&mut self, parent: Kid, name: &str) -> Result<Kid, FsError> {
+        self.check_writable()?;
+        let name = &*self.norm_name(name);
+        validate_name(name, self.config.max_name_len)?;
+        self.check_limits(&parent)?;
+        let kid = with_dir(&parent, |dir| -> Result<Kid, FsError> {
+            self.check_collision(dir, name)?;
+            let ino = self.alloc_inode();
+            let mut new_dir = Dir::new(&self.config, ino);
+            new_dir.parent = Arc::downgrade(&parent);
+            let kid = new_dir.into_kid();
+            dir.kids.insert(name.to_owned(), kid.clone());
+            dir.attr.nlink += 1; // for the kid's ".."
+            self.inodes.insert(ino, kid.clone());
+            Ok(kid)
+        })
+        .ok_or(FsError::NotADirectory)??;
+        self.notify_created(&kid);
+        Ok(kid)
+    }
+
+    // mkdir_all makes every missing directory along path, returning the last one.
+    pub fn mkdir_all(&mut self, path: &str) -> Result<Kid, FsError> {
+        self.check_writable()?;
+        let mut cur = self.root();
+        for comp in split_path(path) {
+            if comp == "." {
+                continue;
+            }
+            if comp == ".." {
+                let parent = cur.read().unwrap().get_parent();
+                if let Some(parent) = parent {
+                    cur = parent;
+                }
+                continue;
+            }
+
+            let locked = cur.read().unwrap();
+            let dir = locked.to_dir().ok_or(FsError::NotADirectory)?;
+            let existing = self.find_kid(dir, &comp).map(|(_, kid)| kid.clone());
+            drop(locked);
+            cur = match existing {
+                Some(kid) if kid.read().unwrap().to_dir().is_some() => kid,
+                Some(_) => return Err(FsError::NotADirectory),
+                None => self.new_dir(cur, &comp)?,
+            };
+        }
+        Ok(cur)
+    }
+
+    pub fn symlink(&mut self, parent: Kid, name: &str, target: &str) -> Result<Kid, FsError> {
+        self.check_writable()?;
+        let name = &*self.norm_name(name);
+        validate_name(name, self.config.max_name_len)?;
+        self.check_limits(&parent)?;
+        let kid = with_dir(&parent, |dir| -> Result<Kid, FsError> {
+            self.check_collision(dir, name)?;
+            let ino = self.alloc_inode();
+            let mut link = Symlink::new(&self.config, ino, target);
+            link.parent = Arc::downgrade(&parent);
+            let kid = link.into_kid();
+            dir.kids.insert(name.to_owned(), kid.clone());
+            self.inodes.insert(ino, kid.clone());
+            Ok(kid)
+        })
+        .ok_or(FsError::NotADirectory)??;
+        self.notify_created(&kid);
+        Ok(kid)
+    }
+
+    // mknod makes an empty named pipe or socket, as kind says.
+    pub fn mknod(&mut self, parent: Kid, name: &str, kind: FileType) -> Result<Kid, FsError> {
+        match kind {
+            FileType::NamedPipe | FileType::Socket => self.new_special(parent, name, kind, 0),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+
+    // mknod_dev makes a char or block device node with device number rdev.
+    pub fn mknod_dev(
+        &mut self,
+        parent: Kid,
+        name: &str,
+        kind: FileType,
+        rdev: u32,
+    ) -> Result<Kid, FsError> {
+        match kind {
+            FileType::CharDevice | FileType::BlockDevice => {
+                self.new_special(parent, name, kind, rdev)
+            }
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+
+    fn new_special(
+        &mut self,
+        parent: Kid,
+        name: &str,
+        kind: FileType,
+        rdev: u32,
+    ) -> Result<Kid, FsError> {
+        self.check_writable()?;
+        let name = &*self.norm_name(name);
+        validate_name(name, self.config.max_name_len)?;
+        self.check_limits(&parent)?;
+        let kid = with_dir(&parent, |dir| -> Result<Kid, FsError> {
+            self.check_collision(dir, name)?;
+            let ino = self.alloc_inode();
+            let mut node = Special::new(&self.config, ino, kind, rdev);
+            node.parent = Arc::downgrade(&parent);
+            let kid = node.into_kid();
+            dir.kids.insert(name.to_owned(), kid.clone());
+            self.inodes.insert(ino, kid.clone());
+            Ok(kid)
+        })
+        .ok_or(FsError::NotADirectory)??;
+        self.notify_created(&kid);
+        Ok(kid)
+    }
+
+    // link adds new_name under new_parent as another name for target.
+    // the node keeps its first parent for path_of.
+    pub fn link(&mut self, target: Kid, new_parent: Kid, new_name: &str) -> Result<Kid, FsError> {
+        self.check_writable()?;
+        let new_name = &*self.norm_name(new_name);
+        validate_name(new_name, self.config.max_name_len)?;
+        if target.read().unwrap().to_dir().is_some() {
+            return Err(FsError::IsADirectory);
+        }
+        // a node whose last name is gone has been forgotten, and its inode
+        // may already belong to something else.
+        let live = self.inodes.get(&kid_ino(&target));
+        if !live.is_some_and(|kid| Arc::ptr_eq(kid, &target)) {
+            return Err(FsError::NotFound);
+        }
+        let mut locked = new_parent.write().unwrap();
+        let dir = locked.to_mut_dir().ok_or(FsError::NotADirectory)?;
+        self.check_collision(dir, new_name)?;
+        dir.kids.insert(new_name.to_owned(), target.clone());
+        drop(locked);
+
+        let mut locked = target.write().unwrap();
+        let attr = locked.get_mut_attr();
+        attr.nlink += 1;
+        attr.ctime = time::SystemTime::now();
+        let ino = attr.ino;
+        drop(locked);
+        if self.events.is_some() {
+            if let Some(dir) = self.path_of(&new_parent) {
+                let path = join_name(&dir, new_name);
+                let parent = kid_ino(&new_parent);
+                self.notify(FsEvent::Created { ino, parent, path });
+            }
+        }
+        Ok(target)
+    }
+
+    // readlink gives the target of a symlink.
+    pub fn readlink(&self, kid: Kid) -> Result<String, FsError> {
+        let locked = kid.read().unwrap();
+        let link = locked.to_symlink().ok_or(FsError::NotASymlink)?;
+        Ok(link.target.clone())
+    }
+
+    // copy_file makes a new file under dst_parent with a copy of src's data and
+    // permissions. the copy gets its own inode and fresh timestamps.
+    pub fn copy_file(&mut self, src: Kid, dst_parent: Kid, dst_name: &str) -> Result<Kid, FsError> {
+        self.check_writable()?;
+        let (data, perm) = {
+            let locked = src.read().unwrap();
+            if locked.to_dir().is_some() {
+                return Err(FsError::IsADirectory);
+            }
+            let file = locked.to_file().ok_or(FsError::InvalidArgument)?;
+            (file.data.clone(), file.attr.perm)
+        };
+        let kid = self.new_file(dst_parent, dst_name, &data)?;
+        kid.write().unwrap().get_mut_attr().perm = perm;
+        Ok(kid)
+    }
+
+    // fork gives an independent copy of the whole tree, with the same inode
+    // numbers, contents, config and limits. hard links stay shared within the
+    // copy. subscribers, open handles and the backend stay with the original,
+    // and the copy keeps its data in memory.
+    pub fn fork(&self) -> Fs {
+        let mut inodes = HashMap::new();
+        let root = fork_node(&self.root, &mut inodes);
+
+        // point each copy at the copy of its parent, so a hard linked
+        // node keeps the same first parent as in the original.
+        for (ino, kid) in &self.inodes {
+            let parent = kid.read().unwrap().get_parent();
+            if let Some(parent) = parent {
+                let parent = Arc::downgrade(&inodes[&kid_ino(&parent)]);
+                inodes[ino].write().unwrap().set_parent(parent);
+            }
+        }
+        Fs {
+            inode_alloc: self.inode_alloc,
+            root,
+            inodes,
+            free_inodes: self.free_inodes.clone(),
+            generations: self.generations.clone(),
+            max_depth: self.max_depth,
+            used_bytes: self.used_bytes,
+            config: self.config,
+            events: None,
+            handle_alloc: 0,
+            handles: HashMap::new(),
+            free_handles: Vec::new(),
+            backend: Box::new(MemoryBackend),
+        }
+    }
+
+    // copy_tree recreates the subtree at src_dir under dst_parent, giving every
+    // node in the copy its own inode.
+    pub fn copy_tree(
+        &mut self,
+        src_dir: Kid,
+        dst_parent: Kid,
+        dst_name: &str,
+    ) -> Result<Kid, FsError> {
+        self.check_writable()?;
+        if src_dir.read().unwrap().to_dir().is_none() {
+            return Err(FsError::NotADirectory);
+        }
+        // copying a dir into itself would never finish.
+        if contains(&src_dir, &dst_parent) {
+            return Err(FsError::InvalidArgument);
+        }
+        self.copy_node(src_dir, dst_parent, dst_name, 0)
+    }
+
+    fn copy_node(
+        &mut self,
+        src: Kid,
+        dst_parent: Kid,
+        name: &str,
+        depth: usize,
+    ) -> Result<Kid, FsError> {
+        if depth > self.max_depth {
+            return Err(FsError::PathTooLong);
+        }
+        let locked = src.read().unwrap();
+        if let Some(link) = locked.to_symlink() {
+            let target = link.target.clone();
+            drop(locked);
+            return self.symlink(dst_parent, name, &target);
+        }
+        if let Some(node) = locked.to_special() {
+            let attr = node.attr;
+            drop(locked);
+            let kid = self.new_special(dst_parent, name, attr.kind, attr.rdev)?;
+            kid.write().unwrap().get_mut_attr().perm = attr.perm;
+            return Ok(kid);
+        }
+        let (perm, kids) = match locked.to_dir() {
+            Some(dir) => (
+                dir.attr.perm,
+                dir.kids
+                    .iter()
+                    .map(|(nm, kid)| (nm.clone(), kid.clone()))
+                    .collect::<Vec<_>>(),
+            ),
+            None => {
+                drop(locked);
+                return self.copy_file(src, dst_parent, name);
+            }
+        };
+        drop(locked);
+
+        let dir = self.new_dir(dst_parent, name)?;
+        dir.write().unwrap().get_mut_attr().perm = perm;
+        for (nm, kid) in kids {
+            self.copy_node(kid, dir.clone(), &nm, depth + 1)?;
+        }
+        Ok(dir)
+    }
+
+    // read_at gives up to size bytes of the file from offset, and none past the end.
+    pub fn read_at(&self, kid: Kid, offset: usize, size: usize) -> Result<Vec<u8>, FsError> {
+        let locked = kid.read().unwrap();
+        if locked.to_dir().is_some() {
+            return Err(FsError::IsADirectory);
+        }
+        let file = locked.to_file().ok_or(FsError::InvalidArgument)?;
+        let start = cmp::min(offset, file.data.len());
+        let end = cmp::min(start.saturating_add(size), file.data.len());
+        Ok(file.data[start..end].to_vec())
+    }
+
+    // write_file writes data into the file at offset, returning the number of bytes written.
+    pub fn write_file(&mut self, kid: Kid, offset: usize, dat: &[u8]) -> Result<usize, FsError> {
+        self.check_writable()?;
+        let mut locked = kid.write().unwrap();
+        if locked.to_dir().is_some() {
+            return Err(FsError::IsADirectory);
+        }
+        let file = locked.to_mut_file().ok_or(FsError::InvalidArgument)?;
+        let old_size = file.attr.size;
+        let end = write_end(offset, dat)?;
+        self.check_space(old_size, cmp::max(old_size, end as u64))?;
+        file.write(offset, end, dat);
+        self.used_bytes = self.used_bytes - old_size + file.attr.size;
+        drop(locked);
+        self.notify(FsEvent::Modified { ino: kid_ino(&kid) });
+        Ok(dat.len())
+    }
+
+    // append adds data to the end of the file, returning its new length.
+    pub fn append(&mut self, kid: Kid, dat: &[u8]) -> Result<usize, FsError> {
+        self.check_writable()?;
+        let mut locked = kid.write().unwrap();
+        if locked.to_dir().is_some() {
+            return Err(FsError::IsADirectory);
+        }
+        let file = locked.to_mut_file().ok_or(FsError::InvalidArgument)?;
+        let old_size = file.attr.size;
+        let (offset, end) = (file.data.len(), write_end(file.data.len(), dat)?);
+        self.check_space(old_size, end as u64)?;
+        file.write(offset, end, dat);
+        self.used_bytes += dat.len() as u64;
+        let len = file.data.len();
+        drop(locked);
+        self.notify(FsEvent::Modified { ino: kid_ino(&kid) });
+        Ok(len)
+    }
+
+    // truncate resizes a file to new_len bytes, zero filling if it grows.
+    pub fn truncate(&mut self, kid: Kid, new_len: usize) -> Result<(), FsError> {
+        self.check_writable()?;
+        let mut locked = kid.write().unwrap();
+        if locked.to_dir().is_some() {
+            return Err(FsError::IsADirectory);
+        }
+        let file = locked.to_mut_file().ok_or(FsError::InvalidArgument)?;
+        let old_size = file.attr.size;
+        self.check_space(old_size, new_len as u64)?;
+        file.truncate(new_len);
+        self.used_bytes = self.used_bytes - old_size + file.attr.size;
+        drop(locked);
+        self.notify(FsEvent::Modified { ino: kid_ino(&kid) });
+        Ok(())
+    }
+
+    // unlink removes a non-directory entry from parent, returning it.
+    pub fn unlink(&mut self, parent: Kid, name: &str) -> Result<Kid, FsError> {
+        self.unlink_checked(parent, name, None)
+    }
+
+    // unlink_checked is unlink on behalf of a caller with the given uid. if
+    // parent's sticky bit is set, only the owner of the entry or of parent
+    // may remove it, or root. with no caller it is just unlink.
+    pub fn unlink_checked(
+        &mut self,
+        parent: Kid,
+        name: &str,
+        caller: Option<u32>,
+    ) -> Result<Kid, FsError> {
+        let parent_ino = log_ino(&parent);
+        let res = self.unlink_inner(parent, name, caller);
+        if let Some(ino) = parent_ino {
+            log_op(format_args!("unlink {ino}/{name}"), &res);
+        }
+        res
+    }
+
+    fn unlink_inner(
+        &mut self,
+        parent: Kid,
+        name: &str,
+        caller: Option<u32>,
+    ) -> Result<Kid, FsError> {
+        self.check_writable()?;
+        let mut locked = parent.write().unwrap();
+        let dir = locked.to_mut_dir().ok_or(FsError::NotADirectory)?;
+        let (key, kid) = self.find_kid(dir, name).ok_or(FsError::NotFound)?;
+        let kid_locked = kid.read().unwrap();
+        if kid_locked.to_dir().is_some() {
+            return Err(FsError::IsADirectory);
+        }
+        check_sticky(&dir.attr, kid_locked.get_attr(), caller)?;
+        drop(kid_locked);
+        let key = key.clone();
+        let kid = dir.kids.remove(&key).unwrap();
+        let parent_ino = dir.attr.ino;
+        drop(locked);
+        self.drop_link(&kid, parent_ino, &key);
+        Ok(kid)
+    }
+
+    // rmdir removes an empty directory entry from parent, returning it.
+    pub fn rmdir(&mut self, parent: Kid, name: &str) -> Result<Kid, FsError> {
+        self.rmdir_checked(parent, name, None)
+    }
+
+    // rmdir_checked is rmdir on behalf of a caller with the given uid, kept
+    // out of sticky dirs as unlink_checked is.
+    pub fn rmdir_checked(
+        &mut self,
+        parent: Kid,
+        name: &str,
+        caller: Option<u32>,
+    ) -> Result<Kid, FsError> {
+        let parent_ino = log_ino(&parent);
+        let res = self.rmdir_inner(parent, name, caller);
+        if let Some(ino) = parent_ino {
+            log_op(format_args!("rmdir {ino}/{name}"), &res);
+        }
+        res
+    }
+
+    fn rmdir_inner(
+        &mut self,
+        parent: Kid,
+        name: &str,
+        caller: Option<u32>,
+    ) -> Result<Kid, FsError> {
+        self.check_writable()?;
+        let mut locked = parent.write().unwrap();
+        let dir = locked.to_mut_dir().ok_or(FsError::NotADirectory)?;
+        let (key, kid) = self.find_kid(dir, name).ok_or(FsError::NotFound)?;
+        let (key, kid) = (key.clone(), kid.clone());
+
+        // hold the kid's lock until it is unlinked so nothing is added in the meantime.
+        let mut kid_locked = kid.write().unwrap();
+        let kid_dir = kid_locked.to_dir().ok_or(FsError::NotADirectory)?;
+        check_sticky(&dir.attr, &kid_dir.attr, caller)?;
+        if !kid_dir.kids.is_empty() {
+            return Err(FsError::NotEmpty);
+        }
+        dir.kids.remove(&key);
+        dir.attr.nlink -= 1;
+        let parent_ino = dir.attr.ino;
+        kid_locked.set_parent(Weak::new());
+        let ino = kid_locked.get_attr().ino;
+        self.release_inode(ino);
+        drop(kid_locked);
+        drop(locked);
+        self.notify_removed(ino, parent_ino, &key);
+        Ok(kid)
+    }
+
+    // rename moves old_parent/old_name to new_parent/new_name, replacing any
+    // compatible entry already there. Returns the moved kid.
+    pub fn rename(
+        &mut self,
+        old_parent: Kid,
+        old_name: &str,
+        new_parent: Kid,
+        new_name: &str,
+    ) -> Result<Kid, FsError> {
+        let inos = log_ino(&old_parent).zip(log_ino(&new_parent));
+        let res = self.rename_inner(old_parent, old_name, new_parent, new_name);
+        if let Some((from, to)) = inos {
+            log_op(
+                format_args!("rename {from}/{old_name} -> {to}/{new_name}"),
+                &res,
+            );
+        }
+        res
+    }
+
+    fn rename_inner(
+        &mut self,
+        old_parent: Kid,
+        old_name: &str,
+        new_parent: Kid,
+        new_name: &str,
+    ) -> Result<Kid, FsError> {
+        self.check_writable()?;
+        let new_name = &*self.norm_name(new_name);
+        validate_name(new_name, self.config.max_name_len)?;
+        let locked = old_parent.read().unwrap();
+        let dir = locked.to_dir().ok_or(FsError::NotADirectory)?;
+        let (old_key, kid) = self.find_kid(dir, old_name).ok_or(FsError::NotFound)?;
+        let (old_key, kid) = (old_key.clone(), kid.clone());
+        drop(locked);
+        let old_name = old_key.as_str();
+        if contains(&kid, &new_parent) {
+            // can't move a dir underneath itself.
+            return Err(FsError::InvalidArgument);
+        }
+        let is_dir = kid.read().unwrap().to_dir().is_some();
+
+        if Arc::ptr_eq(&old_parent, &new_parent) {
+            let mut locked = old_parent.write().unwrap();
+            let dir = locked.to_mut_dir().ok_or(FsError::NotADirectory)?;
+            if !dir.kids.get(old_name).is_some_and(|k| Arc::ptr_eq(k, &kid)) {
+                return Err(FsError::NotFound);
+            }
+            let new_key = self
+                .find_kid(dir, new_name)
+                .map(|(key, k)| (key.clone(), Arc::ptr_eq(k, &kid)));
+            let replaced_dir = match &new_key {
+                // the same node already there is left alone, unless this only changes case.
+                Some((key, true)) if key == new_name || key != old_name => return Ok(kid),
+                Some((_, true)) | None => false,
+                Some((key, false)) => can_replace(is_dir, dir.kids.get(key))?,
+            };
+            dir.kids.remove(old_name);
+            let replaced = match new_key {
+                Some((key, false)) => dir.kids.remove(&key).map(|k| (k, key)),
+                _ => None,
+            };
+            let parent_ino = dir.attr.ino;
+            dir.kids.insert(new_name.to_owned(), kid.clone());
+            if replaced_dir {
+                dir.attr.nlink -= 1;
+            }
+            drop(locked);
+            self.notify_renamed(&old_parent, old_name, &new_parent, new_name);
+            if let Some((replaced, key)) = replaced {
+                self.drop_replaced(&replaced, replaced_dir, parent_ino, &key);
+            }
+            return Ok(kid);
+        }
+
+        // lock both parents in inode order so concurrent renames can't deadlock.
+        let old_ino = old_parent.read().unwrap().get_attr().ino;
+        let new_ino = new_parent.read().unwrap().get_attr().ino;
+        let mut old_locked;
+        let mut new_locked;
+        if old_ino < new_ino {
+            old_locked = old_parent.write().unwrap();
+            new_locked = new_parent.write().unwrap();
+        } else {
+            new_locked = new_parent.write().unwrap();
+            old_locked = old_parent.write().unwrap();
+        }
+        let old_dir = old_locked.to_mut_dir().ok_or(FsError::NotADirectory)?;
+        let new_dir = new_locked.to_mut_dir().ok_or(FsError::NotADirectory)?;
+        if !old_dir
+            .kids
+            .get(old_name)
+            .is_some_and(|k| Arc::ptr_eq(k, &kid))
+        {
+            return Err(FsError::NotFound);
+        }
+        let new_key = self
+            .find_kid(new_dir, new_name)
+            .map(|(key, k)| (key.clone(), Arc::ptr_eq(k, &kid)));
+        let replaced_dir = match &new_key {
+            Some((_, true)) => return Ok(kid),
+            Some((key, false)) => can_replace(is_dir, new_dir.kids.get(key))?,
+            None => false,
+        };
+        old_dir.kids.remove(old_name);
+        let replaced = match new_key {
+            Some((key, false)) => new_dir.kids.remove(&key).map(|k| (k, key)),
+            None | Some((_, true)) => None,
+        };
+        new_dir.kids.insert(new_name.to_owned(), kid.clone());
+        if replaced_dir {
+            new_dir.attr.nlink -= 1;
+        }
+        if is_dir {
+            old_dir.attr.nlink -= 1;
+            new_dir.attr.nlink += 1;
+        }
+        kid.write().unwrap().set_parent(Arc::downgrade(&new_parent));
+        drop(old_locked);
+        drop(new_locked);
+        self.notify_renamed(&old_parent, old_name, &new_parent, new_name);
+        if let Some((replaced, key)) = replaced {
+            self.drop_replaced(&replaced, replaced_dir, new_ino, &key);
+        }
+        Ok(kid)
+    }
+
+    // drop_replaced lets go of the entry a rename wrote over at name in the
+    // dir with inode parent. an empty dir has no other names, so it is
+    // forgotten outright.
+    fn drop_replaced(&mut self, replaced: &Kid, is_dir: bool, parent: u64, name: &str) {
+        if is_dir {
+            self.forget(replaced);
+            self.notify_removed(kid_ino(replaced), parent, name);
+        } else {
+            self.drop_link(replaced, parent, name);
+        }
+    }
+
+    // readdir lists dir's entries as (name, kind, inode), with "." and ".." first
+    // and the rest sorted by name.
+    pub fn readdir(&self, dir: Kid) -> Result<Vec<(String, FileType, u64)>, FsError> {
+        let locked = dir.read().unwrap();
+        let ino = locked.get_attr().ino;
+        let parent = locked.get_parent();
+        let kids = locked
+            .to_dir()
+            .ok_or(FsError::NotADirectory)?
+            .kids
+            .iter()
+            .map(|(name, kid)| (name.clone(), kid.clone()))
+            .collect::<Vec<_>>();
+        drop(locked);
+
+        // the root is its own parent.
+        let parent_ino = match parent {
+            Some(parent) => parent.read().unwrap().get_attr().ino,
+            None => ino,
+        };
+        let mut entries = vec![
+            (".".to_owned(), FileType::Directory, ino),
+            ("..".to_owned(), FileType::Directory, parent_ino),
+        ];
+        for (name, kid) in kids {
+            let locked = kid.read().unwrap();
+            let attr = locked.get_attr();
+            entries.push((name, attr.kind, attr.ino));
+        }
+        Ok(entries)
+    }
+
+    // chmod sets kid's permission bits.
+    pub fn chmod(&mut self, kid: Kid, perm: u16) -> Result<(), FsError> {
+        self.check_writable()?;
+        let mut locked = kid.write().unwrap();
+        let attr = locked.get_mut_attr();
+        attr.perm = perm & PERM_MASK;
+        attr.ctime = time::SystemTime::now();
+        drop(locked);
+        self.notify(FsEvent::Modified { ino: kid_ino(&kid) });
+        Ok(())
+    }
+
+    // chown sets kid's owner and group, leaving either alone if None.
+    pub fn chown(&mut self, kid: Kid, uid: Option<u32>, gid: Option<u32>) -> Result<(), FsError> {
+        self.check_writable()?;
+        let mut locked = kid.write().unwrap();
+        let attr = locked.get_mut_attr();
+        if let Some(uid) = uid {
+            attr.uid = uid;
+        }
+        if let Some(gid) = gid {
+            attr.gid = gid;
+        }
+        attr.ctime = time::SystemTime::now();
+        drop(locked);
+        self.notify(FsEvent::Modified { ino: kid_ino(&kid) });
+        Ok(())
+    }
+
+    // set_times sets kid's access and modify times, leaving either alone if None.
+    pub fn set_times(
+        &mut self,
+        kid: Kid,
+        atime: Option<time::SystemTime>,
+        mtime: Option<time::SystemTime>,
+    ) -> Result<(), FsError> {
+        self.check_writable()?;
+        let mut locked = kid.write().unwrap();
+        let attr = locked.get_mut_attr();
+        if let Some(atime) = atime {
+            attr.atime = atime;
+        }
+        if let Some(mtime) = mtime {
+            attr.mtime = mtime;
+        }
+        attr.ctime = time::SystemTime::now();
+        drop(locked);
+        self.notify(FsEvent::Modified { ino: kid_ino(&kid) });
+        Ok(())
+    }
+
+    // set_xattr stores value under name on kid, replacing any old value.
+    pub fn set_xattr(&mut self, kid: Kid, name: &str, value: &[u8]) -> Result<(), FsError> {
+        self.check_writable()?;
+        if name.is_empty() || name.contains('\0') {
+            return Err(FsError::NameInvalid);
+        }
+        if value.len() > MAX_XATTR_SIZE {
+            return Err(FsError::XattrTooBig);
+        }
+        let mut locked = kid.write().unwrap();
+        let xattrs = locked.get_mut_xattrs().ok_or(FsError::NotSupported)?;
+        xattrs.insert(name.to_owned(), value.to_vec());
+        locked.get_mut_attr().ctime = time::SystemTime::now();
+        drop(locked);
+        self.notify(FsEvent::Modified { ino: kid_ino(&kid) });
+        Ok(())
+    }
+
+    pub fn get_xattr(&self, kid: Kid, name: &str) -> Result<Vec<u8>, FsError> {
+        let locked = kid.read().unwrap();
+        let xattrs = locked.get_xattrs().ok_or(FsError::NotSupported)?;
+        xattrs.get(name).cloned().ok_or(FsError::NoXattr)
+    }
+
+    // list_xattr gives the names of kid's xattrs in sorted order.
+    pub fn list_xattr(&self, kid: Kid) -> Result<Vec<String>, FsError> {
+        let locked = kid.read().unwrap();
+        let xattrs = locked.get_xattrs().ok_or(FsError::NotSupported)?;
+        let mut names: Vec<String> = xattrs.keys().cloned().collect();
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn remove_xattr(&mut self, kid: Kid, name: &str) -> Result<(), FsError> {
+        self.check_writable()?;
+        let mut locked = kid.write().unwrap();
+        let xattrs = locked.get_mut_xattrs().ok_or(FsError::NotSupported)?;
+        xattrs.remove(name).ok_or(FsError::NoXattr)?;
+        locked.get_mut_attr().ctime = time::SystemTime::now();
+        drop(locked);
+        self.notify(FsEvent::Modified { ino: kid_ino(&kid) });
+        Ok(())
+    }
+
+    // walk resolves comps from the root without following symlinks.
+    pub fn walk(&self, comps: Vec<String>) -> Result<Kid, FsError> {
+        self.walk_links(comps, 0)
+    }
+
+    // walk_from resolves comps from start without following symlinks. ".."
+    // follows parent links, so it can climb above start as far as the root.
+    pub fn walk_from(&self, start: Kid, comps: Vec<String>) -> Result<Kid, FsError> {
+        self.walk_links_from(start, comps, 0)
+    }
+
+    // walk_links resolves comps from the root, following up to max_links symlinks.
+    // With max_links of zero, symlinks are not followed at all.
+    pub fn walk_links(&self, comps: Vec<String>, max_links: usize) -> Result<Kid, FsError> {
+        self.walk_links_from(self.root.clone(), comps, max_links)
+    }
+
+    // walk_links_from is walk_links starting at start rather than the root.
+    pub fn walk_links_from(
+        &self,
+        start: Kid,
+        comps: Vec<String>,
+        max_links: usize,
+    ) -> Result<Kid, FsError> {
+        self.walk_inner(start, comps, max_links, None)
+    }
+
+    // walk_checked is walk for a caller with the given uid and gid, who may
+    // only look in dirs they have execute permission on. a walk through any
+    // other dir fails with PermissionDenied. with no caller it is just walk.
+    pub fn walk_checked(
+        &self,
+        comps: Vec<String>,
+        caller: Option<(u32, u32)>,
+    ) -> Result<Kid, FsError> {
+        self.walk_inner(self.root.clone(), comps, 0, caller)
+    }
+
+    fn walk_inner(
+        &self,
+        start: Kid,
+        comps: Vec<String>,
+        max_links: usize,
+        caller: Option<(u32, u32)>,
+    ) -> Result<Kid, FsError> {
+        // println!("walking {comps:?}");
+        let mut comps = VecDeque::from(comps);
+        let mut depth = 0;
+        let mut links = 0;
+        let mut cur = start;
+        while let Some(comp) = comps.pop_front() {
+            //println!("comp {comp} current {}", cur.read().unwrap());
+            if comp.is_empty() {
+                continue;
+            }
+            depth += 1;
+            if depth > self.max_depth {
+                return Err(FsError::PathTooLong);
+            }
+
+            let mut next = None;
+
+            // find out what's next under lock. ".." follows the parent link,
+            // which the root doesn't have, so "/.." stays at the root.
+            let locked = cur.read().unwrap();
+            if let Some(dir) = locked.to_dir() {
+                if let Some((uid, gid)) = caller {
+                    if !permits(&dir.attr, uid, gid, 0o1) {
+                        return Err(FsError::PermissionDenied);
+                    }
+                }
+                if comp == "." {
+                    // keep cur...
+                } else if comp == ".." {
+                    next = locked.get_parent();
+                } else if let Some((_, kid)) = self.find_kid(dir, &comp) {
+                    next = Some(kid.clone());
+                } else {
+                    //println!("not found");
+                    return Err(FsError::NotFound);
+                }
+            } else {
+                //println!("cur not dir");
+                return Err(FsError::NotADirectory);
+            }
+            drop(locked);
+
+            // expand symlinks in place of the component that named them.
+            let target = match &next {
+                Some(kid) if max_links > 0 => kid
+                    .read()
+                    .unwrap()
+                    .to_symlink()
+                    .map(|link| link.target.clone()),
+                _ => None,
+            };
+            if let Some(target) = target {
+                links += 1;
+                if links > max_links {
+                    return Err(FsError::TooManyLinks);
+                }
+                if target.starts_with('/') {
+                    cur = self.root.clone();
+                }
+                for comp in split_path(&target).into_iter().rev() {
+                    comps.push_front(comp);
+                }
+                continue;
+            }
+
+            // move to next
+            if let Some(next) = next {
+                cur = next;
+            }
+        }
+        //println!("found {}", cur.read().unwrap());
+        Ok(cur)
+    }
+
+    // walk_parent resolves all but the last component, returning the parent dir,
+    // the final name, and the named kid if it exists.
+    pub fn walk_parent(&self, comps: Vec<String>) -> Result<(Kid, String, Option<Kid>), FsError> {
+        self.walk_parent_from(self.root.clone(), comps)
+    }
+
+    // walk_parent_from is walk_parent starting at start rather than the root.
+    pub fn walk_parent_from(
+        &self,
+        start: Kid,
+        mut comps: Vec<String>,
+    ) -> Result<(Kid, String, Option<Kid>), FsError> {
+        comps.retain(|comp| !comp.is_empty());
+        let name = comps.pop().ok_or(FsError::NameInvalid)?;
+        if name == "." || name == ".." {
+            return Err(FsError::NameInvalid);
+        }
+        let parent = self.walk_from(start, comps)?;
+        let locked = parent.read().unwrap();
+        let dir = locked.to_dir().ok_or(FsError::NotADirectory)?;
+        let kid = self.find_kid(dir, &name).map(|(_, kid)| kid.clone());
+        drop(locked);
+        Ok((parent, name, kid))
+    }
+
+    pub fn test_walk(&self, path: &str) -> Option<Kid> {
+        let comps = split_path(path);
+        println!("walking {path} {comps:?}");
+        let r = self.walk(comps);
+        match r {
+            Ok(ref kid) => println!("got {}", kid.read().unwrap()),
+            Err(ref e) => println!("error {}", e),
+        }
+        println!();
+        r.ok()
+    }
+
+    // path_of gives kid's absolute path, or None if kid is no longer in the tree.
+    pub fn path_of(&self, kid: &Kid) -> Option<String> {
+        let mut names = Vec::new();
+        let mut cur = kid.clone();
+        while !Arc::ptr_eq(&cur, &self.root) {
+            let parent = cur.read().unwrap().get_parent()?;
+            let name = parent
+                .read()
+                .unwrap()
+                .to_dir()?
+                .kids
+                .iter()
+                .find(|(_, k)| Arc::ptr_eq(k, &cur))
+                .map(|(name, _)| name.clone())?;
+            names.push(name);
+            cur = parent;
+        }
+        names.reverse();
+        Some(format!("/{}", names.join("/")))
+    }
+
+    // iter visits every node depth first, with each dir's kids in name order.
+    // the walk is collected up front so no locks are held while iterating.
+    pub fn iter(&self) -> impl Iterator<Item = (String, Kid)> {
+        let mut out = Vec::new();
+        collect_tree(self.root(), "/".to_owned(), &mut out);
+        out.into_iter()
+    }
+
+    // find gives the path and node of everything in the tree pred is true of,
+    // depth first. pred sees each node under its own read lock only, so it may
+    // lock other nodes itself.
+    pub fn find(&self, pred: impl Fn(&dyn DispElem) -> bool) -> Vec<(String, Kid)> {
+        self.iter()
+            .filter(|(_, kid)| pred(&**kid.read().unwrap()))
+            .collect()
+    }
+
+    // recompute_nlink resets every dir's nlink to 2 plus its number of subdirs.
+    // the counts are kept as dirs come and go, so this is only a backstop.
+    pub fn recompute_nlink(&mut self) {
+        recompute_nlink(&self.root);
+    }
+
+    // compact gives back the spare capacity file buffers are left with after
+    // truncates and overwrites, returning how many bytes were freed. the
+    // contents are unchanged, so it works on a read-only tree too.
+    pub fn compact(&mut self) -> usize {
+        let mut freed = 0;
+        for kid in self.inodes.values() {
+            if let Some(file) = kid.write().unwrap().to_mut_file() {
+                let before = file.data.capacity();
+                file.data.shrink_to_fit();
+                freed += before - file.data.capacity();
+            }
+        }
+        freed
+    }
+
+    pub fn show_tree(&self) {
+        show_tree(self.root(), ".", 0);
+    }
+
+    pub fn fmt_tree(&self, w: &mut impl fmt::Write) -> fmt::Result {
+        fmt_tree(w, self.root(), ".", 0)
+    }
+}
+
+// log_ino gives kid's inode for a log message, or None without locking
+// anything when debug logging is off.
+fn log_ino(kid: &Kid) -> Option<u64> {
+    log::log_enabled!(log::Level::Debug).then(|| kid_ino(kid))
+}
+
+// log_op logs a change to the tree and how it turned out.
+fn log_op(op: fmt::Arguments<'_>, res: &Result<Kid, FsError>) {
+    match res {
+        Ok(kid) => log::debug!("{op}: ino {}", kid_ino(kid)),
+        Err(e) => log::debug!("{op}: {e}"),
+    }
+}
+
+// permits says whether attr lets a caller with uid and gid act as mask asks,
+// mask holding read (4), write (2) and execute (1) bits. the owner's bits
+// apply to its owner, then the group's to its group, then everyone else's.
+// root may do anything.
+fn permits(attr: &FileAttr, uid: u32, gid: u32, mask: u16) -> bool {
+    if uid == 0 {
+        return true;
+    }
+    let allowed = if uid == attr.uid {
+        attr.perm >> 6
+    } else if gid == attr.gid {
+        attr.perm >> 3
+    } else {
+        attr.perm
+    };
+    mask & 0o7 & !allowed == 0
+}
+
+// check_sticky refuses to let caller remove an entry with attr kid from a
+// dir with attr dir whose sticky bit is set, unless they own one of them or
+// are root.
+fn check_sticky(dir: &FileAttr, kid: &FileAttr, caller: Option<u32>) -> Result<(), FsError> {
+    match caller {
+        Some(uid) if dir.perm & STICKY != 0 && uid != 0 && uid != dir.uid && uid != kid.uid => {
+            Err(FsError::PermissionDenied)
+        }
+        _ => Ok(()),
+    }
+}
+
+// write_end gives where a write of dat at offset stops. one so far out it
+// would end past usize::MAX is InvalidArgument.
+fn write_end(offset: usize, dat: &[u8]) -> Result<usize, FsError> {
+    offset
+        .checked_add(dat.len())
+        .ok_or(FsError::InvalidArgument)
+}
+
+// with_dir runs f on the dir kid holds, under a single write lock that is
+// dropped before returning. it gives None if kid isn't a dir.
+fn with_dir<R>(kid: &Kid, f: impl FnOnce(&mut Dir) -> R) -> Option<R> {
+    let mut locked = kid.write().unwrap();
+    locked.to_mut_dir().map(f)
+}
+
+fn kid_ino(kid: &Kid) -> u64 {
+    kid.read().unwrap().get_attr().ino
+}
+
+// join_name gives the path of name inside the dir at path.
+fn join_name(path: &str, name: &str) -> String {
+    match path {
+        "/" => format!("/{name}"),
+        _ => format!("{path}/{name}"),
+    }
+}
+
+// can_replace checks if a kid may be renamed over dst, returning whether dst is a dir.
+fn can_replace(is_dir: bool, dst: Option<&Kid>) -> Result<bool, FsError> {
+    let dst = match dst {
+        None => return Ok(false),
+        Some(dst) => dst.read().unwrap(),
+    };
+    match dst.to_dir() {
+        Some(_) if !is_dir => Err(FsError::IsADirectory),
+        Some(dst_dir) if !dst_dir.kids.is_empty() => Err(FsError::NotEmpty),
+        Some(_) => Ok(true),
+        None if is_dir => Err(FsError::NotADirectory),
+        None => Ok(false),
+    }
+}
+
+// contains checks if target is k or somewhere underneath it.
+fn contains(k: &Kid, target: &Kid) -> bool {
+    if Arc::ptr_eq(k, target) {
+        return true;
+    }
+    let kids: Vec<Kid> = match k.read().unwrap().to_dir() {
+        Some(dir) => dir.kids.values().cloned().collect(),
+        None => return false,
+    };
+    kids.iter().any(|kid| contains(kid, target))
+}
+
+// diff compares the trees at a and b, giving their differences in path order.
+// a path only in one tree is reported once, without what's under it.
+pub fn diff(a: Kid, b: Kid) -> Vec<DiffEntry> {
+    let mut out = Vec::new();
+    diff_node(&a, &b, "/", &mut out);
+    out
+}
+
+// NodeInfo is what diff compares, copied out so only one node is locked at a time.
+struct NodeInfo {
+    kind: FileType,
+    perm: u16,
+    contents: Option<Vec<u8>>,
+    kids: BTreeMap<String, Kid>,
+}
+
+fn node_info(k: &Kid) -> NodeInfo {
+    let locked = k.read().unwrap();
+    let attr = locked.get_attr();
+    let contents = match (locked.to_file(), locked.to_symlink()) {
+        (Some(file), _) => Some(file.data.clone()),
+        (_, Some(link)) => Some(link.target.clone().into_bytes()),
+        _ => None,
+    };
+    let kids = match locked.to_dir() {
+        Some(dir) => dir.kids.clone().into_iter().collect(),
+        None => BTreeMap::new(),
+    };
+    NodeInfo {
+        kind: attr.kind,
+        perm: attr.perm,
+        contents,
+        kids,
+    }
+}
+
+fn diff_node(a: &Kid, b: &Kid, path: &str, out: &mut Vec<DiffEntry>) {
+    // a node shared by both trees is the same in both, all the way down.
+    if Arc::ptr_eq(a, b) {
+        return;
+    }
+    let (a, b) = (node_info(a), node_info(b));
+    if a.kind != b.kind {
+        out.push(DiffEntry::Type(path.to_owned()));
+        return;
+    }
+    if a.contents != b.contents {
+        out.push(DiffEntry::Contents(path.to_owned()));
+    }
+    if a.perm != b.perm {
+        out.push(DiffEntry::Perm(path.to_owned()));
+    }
+
+    let names: BTreeSet<&String> = a.kids.keys().chain(b.kids.keys()).collect();
+    for name in names {
+        let kid_path = join_name(path, name);
+        match (a.kids.get(name), b.kids.get(name)) {
+            (Some(ka), Some(kb)) => diff_node(ka, kb, &kid_path, out),
+            (Some(_), None) => out.push(DiffEntry::OnlyInA(kid_path)),
+            (None, _) => out.push(DiffEntry::OnlyInB(kid_path)),
+        }
+    }
+}
+
+// collect_tree appends k at path and everything under it to out.
+fn collect_tree(k: Kid, path: String, out: &mut Vec<(String, Kid)>) {
+    let kids: Vec<(String, Kid)> = match k.read().unwrap().to_dir() {
+        Some(dir) => dir
+            .kids
+            .iter()
+            .map(|(nm, kid)| (nm.clone(), kid.clone()))
+            .collect(),
+        None => Vec::new(),
+    };
+    let prefix = if path == "/" {
+        String::new()
+    } else {
+        path.clone()
+    };
+    out.push((path, k));
+    for (nm, kid) in kids {
+        collect_tree(kid, format!("{prefix}/{nm}"), out);
+    }
+}
+
+// fork_node copies k and everything under it, adding each copy to inodes.
+// a node already in inodes is another link to it, and is shared. the copies
+// are left without parents.
+fn fork_node(k: &Kid, inodes: &mut HashMap<u64, Kid>) -> Kid {
+    let parent = Weak::new();
+    let locked = k.read().unwrap();
+    let attr = *locked.get_attr();
+    if let Some(kid) = inodes.get(&attr.ino) {
+        return kid.clone();
+    }
+    let mut kids = Vec::new();
+    let copy = if let Some(dir) = locked.to_dir() {
+        kids.extend(dir.kids.iter().map(|(nm, kid)| (nm.clone(), kid.clone())));
+        let xattrs = dir.xattrs.clone();
+        let kids = BTreeMap::new();
+        Dir {
+            attr,
+            parent,
+            kids,
+            xattrs,
+        }
+        .into_kid()
+    } else if let Some(file) = locked.to_file() {
+        let (data, xattrs) = (file.data.clone(), file.xattrs.clone());
+        File {
+            attr,
+            parent,
+            data,
+            xattrs,
+        }
+        .into_kid()
+    } else if let Some(link) = locked.to_symlink() {
+        let target = link.target.clone();
+        Symlink {
+            attr,
+            parent,
+            target,
+        }
+        .into_kid()
+    } else if let Some(node) = locked.to_special() {
+        let xattrs = node.xattrs.clone();
+        Special {
+            attr,
+            parent,
+            xattrs,
+        }
+        .into_kid()
+    } else {
+        unreachable!("unknown node type {}", locked)
+    };
+    drop(locked);
+    inodes.insert(attr.ino, copy.clone());
+
+    let forked = kids
+        .into_iter()
+        .map(|(nm, kid)| (nm, fork_node(&kid, inodes)))
+        .collect();
+    if let Some(dir) = copy.write().unwrap().to_mut_dir() {
+        dir.kids = forked;
+    }
+    copy
+}
+
+fn recompute_nlink(k: &Kid) {
+    let mut locked = k.write().unwrap();
+    let dir = match locked.to_mut_dir() {
+        Some(dir) => dir,
+        None => return,
+    };
+    let subdirs: Vec<Kid> = dir
+        .kids
+        .values()
+        .filter(|kid| kid.read().unwrap().to_dir().is_some())
+        .cloned()
+        .collect();
+    dir.attr.nlink = 2 + subdirs.len() as u32;
+    drop(locked);
+    for kid in &subdirs {
+        recompute_nlink(kid);
+    }
+}
+
+pub fn show_tree(k: Kid, name: &str, level: usize) {
+    let mut out = String::new();
+    fmt_tree(&mut out, k, name, level).unwrap();
+    print!("{}", out);
+}
+
+pub fn fmt_tree(w: &mut impl fmt::Write, k: Kid, name: &str, level: usize) -> fmt::Result {
+    if level == 0 {
+        writeln!(w, "Tree:")?;
+    }
+
+    // lock once, and don't hold the lock while visiting kids.
+    let locked = k.read().unwrap();
+    writeln!(
+        w,
+        "{0:>1$}[{2}] {3}: {4}",
+        "",
+        level * 2,
+        Arc::strong_count(&k),
+        name,
+        locked
+    )?;
+    let kids = match locked.to_dir() {
+        Some(dir) => dir
+            .kids
+            .iter()
+            .map(|(nm, kid)| (nm.clone(), kid.clone()))
+            .collect(),
+        None => Vec::new(),
+    };
+    drop(locked);
+
+    for (nm, kid) in kids {
+        fmt_tree(w, kid, &nm, level + 1)?;
+    }
+
+    if level == 0 {
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fs::*;
+
+    #[test]
+    fn test_walk() {
+        fn get_inode(f: Kid) -> u64 {
+            f.read().unwrap().get_attr().ino
+        }
+        fn oget_inode(of: Option<Kid>) -> Option<u64> {
+            of.map(get_inode)
+        }
+        fn walk_gives(fs: &mut Fs, path: &str, want: Kid) {
+            let want_ino = get_inode(want);
+            let targ = fs.test_walk(path);
+            assert_eq!(oget_inode(targ), Some(want_ino));
+        }
+        fn walk_fails(fs: &mut Fs, path: &str) {
+            let targ = fs.test_walk(path);
+            assert_eq!(oget_inode(targ), None);
+        }
+
+        let mut fs = Fs::new();
+        let root = fs.root();
+        let d1 = fs.new_dir(root.clone(), "dir1").unwrap();
+        let d2 = fs.new_dir(fs.root(), "dir2").unwrap();
+        let f1 = fs.new_file(d1.clone(), "f1", b"file1 here").unwrap();
+        let f2 = fs.new_file(d1.clone(), "f2", b"file2 here").unwrap();
+
+        assert_ne!(get_inode(f1.clone()), get_inode(f2.clone()));
+        assert_ne!(get_inode(d1.clone()), get_inode(f2.clone()));
+
+        walk_gives(&mut fs, "/dir1/f1", f1.clone());
+        walk_gives(&mut fs, "dir1/f1", f1.clone());
+        walk_gives(&mut fs, "dir1/f2", f2.clone());
+        walk_gives(&mut fs, "/dir1/../dir2", d2.clone());
+        walk_gives(&mut fs, "//dir2/.././/dir1/f1", f1.clone());
+        walk_gives(&mut fs, "//dir2/.././/dir1/../../../../../..", root.clone());
 
         walk_fails(&mut fs, "/bogus");
         walk_fails(&mut fs, "/dir1/f1/bogus");
     }
+
+    #[test]
+    fn test_split_path() {
+        assert_eq!(
+            split_path("//dir2/.././/dir1/f1"),
+            ["dir2", "..", ".", "dir1", "f1"]
+        );
+        assert_eq!(split_path("dir1/f1/"), ["dir1", "f1"]);
+        assert!(split_path("/").is_empty());
+        assert!(split_path("").is_empty());
+    }
+
+    #[test]
+    fn test_join_path() {
+        assert_eq!(
+            join_path(&split_path("//dir2/.././/dir1/f1")),
+            "/dir2/../dir1/f1"
+        );
+        assert_eq!(join_path(&split_path("dir1/f1/")), "/dir1/f1");
+        assert_eq!(
+            join_path(&["".to_owned(), "a".to_owned(), ".".to_owned()]),
+            "/a"
+        );
+        assert_eq!(join_path(&[]), "/");
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        assert_eq!(canonicalize("//dir2/.././/dir1/f1"), "/dir1/f1");
+        assert_eq!(canonicalize("dir1/f1/"), "/dir1/f1");
+        assert_eq!(canonicalize("/dir1/./f1/."), "/dir1/f1");
+        assert_eq!(canonicalize("/../../dir1/.."), "/");
+        assert_eq!(canonicalize("/a/b/../../../c"), "/c");
+        assert_eq!(canonicalize(""), "/");
+        assert_eq!(canonicalize("..."), "/...");
+    }
+
+    #[test]
+    fn test_walk_from() {
+        let mut fs = Fs::new();
+        let d1 = fs.new_dir(fs.root(), "dir1").unwrap();
+        let d2 = fs.new_dir(fs.root(), "dir2").unwrap();
+        let f1 = fs.new_file(d1.clone(), "f1", b"file1 here").unwrap();
+
+        let walk = |fs: &Fs, start: &Kid, path: &str| fs.walk_from(start.clone(), split_path(path));
+        assert!(Arc::ptr_eq(&walk(&fs, &d1, "../dir2").unwrap(), &d2));
+        assert!(Arc::ptr_eq(&walk(&fs, &d1, "f1").unwrap(), &f1));
+        assert!(Arc::ptr_eq(&walk(&fs, &d1, "").unwrap(), &d1));
+        assert!(Arc::ptr_eq(
+            &walk(&fs, &d1, "../../..").unwrap(),
+            &fs.root()
+        ));
+        assert_eq!(walk(&fs, &d1, "dir2").err(), Some(FsError::NotFound));
+        assert_eq!(walk(&fs, &f1, "x").err(), Some(FsError::NotADirectory));
+    }
+
+    #[test]
+    fn test_mkdir_all() {
+        let mut fs = Fs::new();
+        let z = fs.mkdir_all("/x/y/z").unwrap();
+        assert!(Arc::ptr_eq(&fs.test_walk("/x/y/z").unwrap(), &z));
+        assert_eq!(fs.path_of(&z).as_deref(), Some("/x/y/z"));
+
+        let again = fs.mkdir_all("x//y/./z/").unwrap();
+        assert!(Arc::ptr_eq(&again, &z));
+        assert_eq!(fs.readdir(fs.root()).unwrap().len(), 3);
+
+        let w = fs.mkdir_all("/x/y/z/../w").unwrap();
+        assert_eq!(fs.path_of(&w).as_deref(), Some("/x/y/w"));
+        assert!(Arc::ptr_eq(&fs.mkdir_all("/").unwrap(), &fs.root()));
+
+        fs.new_file(z, "f", b"HELLO").unwrap();
+        let err = fs.mkdir_all("/x/y/z/f/deeper").err();
+        assert_eq!(err, Some(FsError::NotADirectory));
+    }
+
+    #[test]
+    fn test_symlink() {
+        let mut fs = Fs::new();
+        let root = fs.root();
+        let d1 = fs.new_dir(root.clone(), "dir1").unwrap();
+        let d2 = fs.new_dir(root.clone(), "dir2").unwrap();
+        let f1 = fs.new_file(d1.clone(), "f1", b"file1 here").unwrap();
+        let abs = fs.symlink(d2.clone(), "abs", "/dir1/f1").unwrap();
+        fs.symlink(d2.clone(), "rel", "../dir1").unwrap();
+        fs.symlink(d2.clone(), "loop", "loop").unwrap();
+
+        let attr = *abs.read().unwrap().get_attr();
+        assert_eq!(attr.kind, FileType::Symlink);
+        assert_eq!(attr.size, 8);
+
+        // without following, links are returned as-is and aren't dirs.
+        assert!(Arc::ptr_eq(
+            &fs.walk(split_path("/dir2/abs")).unwrap(),
+            &abs
+        ));
+        let err = fs.walk(split_path("/dir2/rel/f1")).err();
+        assert_eq!(err, Some(FsError::NotADirectory));
+
+        let walk = |fs: &mut Fs, path| fs.walk_links(split_path(path), MAX_SYMLINK_HOPS);
+        assert!(Arc::ptr_eq(&walk(&mut fs, "/dir2/abs").unwrap(), &f1));
+        assert!(Arc::ptr_eq(&walk(&mut fs, "/dir2/rel/f1").unwrap(), &f1));
+        assert!(Arc::ptr_eq(
+            &walk(&mut fs, "/dir2/rel/../dir2").unwrap(),
+            &d2
+        ));
+        assert_eq!(
+            walk(&mut fs, "/dir2/loop").err(),
+            Some(FsError::TooManyLinks)
+        );
+
+        // ".." after a link leads to the parent of where the link went.
+        let sub = fs.new_dir(d1.clone(), "sub").unwrap();
+        fs.symlink(root.clone(), "deep", "dir1/sub").unwrap();
+        assert!(Arc::ptr_eq(&walk(&mut fs, "/deep").unwrap(), &sub));
+        assert!(Arc::ptr_eq(&walk(&mut fs, "/deep/..").unwrap(), &d1));
+        assert!(Arc::ptr_eq(&walk(&mut fs, "/deep/../f1").unwrap(), &f1));
+
+        let err = fs.walk_links(split_path("/dir2/rel/f1"), 0).err();
+        assert_eq!(err, Some(FsError::NotADirectory));
+    }
+
+    #[test]
+    fn test_readlink() {
+        let mut fs = Fs::new();
+        let d1 = fs.new_dir(fs.root(), "dir1").unwrap();
+        let f1 = fs.new_file(d1, "f1", b"HELLO").unwrap();
+        let link = fs.symlink(fs.root(), "link", "/dir1/f1").unwrap();
+        assert_eq!(fs.readlink(link).unwrap(), "/dir1/f1");
+        assert_eq!(fs.readlink(f1).err(), Some(FsError::NotASymlink));
+        assert_eq!(FsError::NotASymlink.errno(), libc::EINVAL);
+    }
+
+    #[test]
+    fn test_walk_depth() {
+        let mut fs = Fs::new();
+        let root = fs.root();
+        let d1 = fs.new_dir(root.clone(), "dir1").unwrap();
+        let d2 = fs.new_dir(root.clone(), "dir2").unwrap();
+        let f1 = fs.new_file(d1.clone(), "f1", b"file1 here").unwrap();
+        let a = fs.new_dir(root.clone(), "a").unwrap();
+        fs.symlink(a.clone(), "self_link", ".").unwrap();
+
+        let walk = |fs: &mut Fs, path: &str| fs.walk_links(split_path(path), MAX_SYMLINK_HOPS);
+        assert!(Arc::ptr_eq(
+            &walk(&mut fs, "//dir2/.././/dir1/f1").unwrap(),
+            &f1
+        ));
+        assert!(Arc::ptr_eq(
+            &walk(&mut fs, "/a/self_link/self_link").unwrap(),
+            &a
+        ));
+        let path = format!("/a{}", "/self_link".repeat(100));
+        assert_eq!(walk(&mut fs, &path).err(), Some(FsError::TooManyLinks));
+
+        let path = format!("/dir2{}", "/.".repeat(MAX_WALK_DEPTH - 1));
+        assert!(Arc::ptr_eq(&walk(&mut fs, &path).unwrap(), &d2));
+        let path = format!("/dir2{}", "/.".repeat(MAX_WALK_DEPTH));
+        assert_eq!(walk(&mut fs, &path).err(), Some(FsError::PathTooLong));
+
+        fs.set_max_depth(2);
+        assert!(walk(&mut fs, "/dir1/f1").is_ok());
+        assert_eq!(
+            walk(&mut fs, "/a/self_link").err(),
+            Some(FsError::PathTooLong)
+        );
+    }
+
+    #[test]
+    fn test_validate_name() {
+        let mut fs = Fs::new();
+        let root = fs.root();
+        let d1 = fs.new_dir(root.clone(), "dir1").unwrap();
+        fs.new_file(d1.clone(), "f1", b"file1 here").unwrap();
+
+        for name in ["", ".", "..", "a/b", "/", "a\0b"] {
+            let err = fs.new_file(d1.clone(), name, b"bad").err();
+            assert_eq!(err, Some(FsError::NameInvalid), "{:?}", name);
+            assert_eq!(
+                fs.new_dir(d1.clone(), name).err(),
+                Some(FsError::NameInvalid)
+            );
+            let err = fs.symlink(d1.clone(), name, "/").err();
+            assert_eq!(err, Some(FsError::NameInvalid));
+            let err = fs.rename(d1.clone(), "f1", root.clone(), name).err();
+            assert_eq!(err, Some(FsError::NameInvalid));
+        }
+        assert_eq!(fs.readdir(d1).unwrap().len(), 3);
+        assert!(fs.new_file(root, "...", b"ok").is_ok());
+    }
+
+    #[test]
+    fn test_name_too_long() {
+        let mut fs = Fs::new();
+        let root = fs.root();
+        let long = "x".repeat(MAX_NAME_LEN + 1);
+        let err = fs.new_file(root.clone(), &long, b"bad").err();
+        assert_eq!(err, Some(FsError::NameTooLong));
+        assert_eq!(fs.new_dir(root.clone(), &long).err(), err);
+        assert_eq!(fs.symlink(root.clone(), &long, "/").err(), err);
+        assert_eq!(err.unwrap().errno(), libc::ENAMETOOLONG);
+
+        let f = fs.new_file(root.clone(), &long[1..], b"ok").unwrap();
+        assert_eq!(
+            fs.rename(root.clone(), &long[1..], root.clone(), &long)
+                .err(),
+            err
+        );
+        assert_eq!(fs.link(f, root.clone(), &long).err(), err);
+
+        // the limit is in bytes, so 128 two byte chars are too many.
+        let wide = "\u{e9}".repeat(128);
+        assert_eq!(wide.chars().count(), 128);
+        assert_eq!(fs.new_file(root.clone(), &wide, b"bad").err(), err);
+        assert!(fs.new_file(root, &wide[2..], b"ok").is_ok());
+    }
+
+    #[test]
+    fn test_show_tree() {
+        let mut fs = Fs::new();
+        let a = fs.new_dir(fs.root(), "a").unwrap();
+        let b = fs.new_dir(a.clone(), "b").unwrap();
+        let c = fs.new_dir(b.clone(), "c").unwrap();
+        fs.new_file(c.clone(), "f", b"deep").unwrap();
+
+        // a locking bug would hang, so fail on a timeout instead.
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            fs.show_tree();
+            show_tree(b, "b", 0);
+            tx.send(()).unwrap();
+        });
+        rx.recv_timeout(time::Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_fmt_tree() {
+        let mut fs = Fs::new();
+        let d1 = fs.new_dir(fs.root(), "dir1").unwrap();
+        fs.new_file(d1, "f1", b"HELLO").unwrap();
+
+        let mut out = String::new();
+        fs.fmt_tree(&mut out).unwrap();
+        let want = "Tree:\n\
+                    [3] .: Dir([\"dir1\"])\n  \
+                    [3] dir1: Dir([\"f1\"])\n    \
+                    [3] f1: File(HELLO)\n\
+                    \n";
+        assert_eq!(out, want);
+    }
+
+    #[test]
+    fn test_fmt_tree_symlink() {
+        let mut fs = Fs::new();
+        let d1 = fs.new_dir(fs.root(), "dir1").unwrap();
+        fs.symlink(d1, "l", "/etc/passwd").unwrap();
+
+        let mut out = String::new();
+        fs.fmt_tree(&mut out).unwrap();
+        let want = "Tree:\n\
+                    [3] .: Dir([\"dir1\"])\n  \
+                    [3] dir1: Dir([\"l\"])\n    \
+                    [3] l: Symlink(-> /etc/passwd)\n\
+                    \n";
+        assert_eq!(out, want);
+    }
+
+    #[test]
+    fn test_getattr() {
+        let mut fs = Fs::new();
+        let f1 = fs.new_file(fs.root(), "f1", b"HELLO").unwrap();
+        let ino = f1.read().unwrap().get_attr().ino;
+
+        let attr = fs.getattr(&f1);
+        assert_eq!(attr.ino, ino);
+        assert_eq!(attr.size, 5);
+        assert_eq!(attr.kind, FileType::RegularFile);
+
+        // the copy doesn't track later changes, or hold the lock.
+        fs.write_file(f1.clone(), 5, b"!").unwrap();
+        assert_eq!(attr.size, 5);
+        assert_eq!(fs.getattr(&f1).size, 6);
+        assert_eq!(fs.getattr(&fs.root()).ino, fuser::FUSE_ROOT_ID);
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut fs = Fs::new();
+        let d1 = fs.new_dir(fs.root(), "dir1").unwrap();
+        fs.new_dir(fs.root(), "dir2").unwrap();
+        fs.new_file(d1.clone(), "f2", b"HI").unwrap();
+        fs.new_file(d1.clone(), "f1", b"HELLO").unwrap();
+
+        let paths: Vec<String> = fs.iter().map(|(path, _)| path).collect();
+        assert_eq!(paths, ["/", "/dir1", "/dir1/f1", "/dir1/f2", "/dir2"]);
+
+        // consumers may lock the kids they are handed.
+        for (path, kid) in fs.iter() {
+            kid.write().unwrap().get_mut_attr().perm = 0o700;
+            assert_eq!(fs.path_of(&kid).unwrap(), path);
+        }
+    }
+
+    #[test]
+    fn test_parallel_reads() {
+        let mut fs = Fs::new();
+        fs.new_dir(fs.root(), "dir1").unwrap();
+        let fs = Arc::new(fs);
+
+        // both readers hold the root's read guard at the barrier, which
+        // would deadlock if readers were serialized.
+        let barrier = Arc::new(std::sync::Barrier::new(2));
+        let (tx, rx) = std::sync::mpsc::channel();
+        for _ in 0..2 {
+            let (fs, barrier, tx) = (fs.clone(), barrier.clone(), tx.clone());
+            std::thread::spawn(move || {
+                let root = fs.root();
+                let locked = root.read().unwrap();
+                barrier.wait();
+                let found = fs.walk(split_path("/dir1")).is_ok();
+                drop(locked);
+                tx.send(found).unwrap();
+            });
+        }
+        for _ in 0..2 {
+            assert!(rx.recv_timeout(time::Duration::from_secs(5)).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_walk_parent() {
+        let mut fs = Fs::new();
+        let d1 = fs.new_dir(fs.root(), "dir1").unwrap();
+        let f1 = fs.new_file(d1.clone(), "f1", b"file1 here").unwrap();
+
+        let (parent, name, kid) = fs.walk_parent(split_path("/dir1/f1")).unwrap();
+        assert!(Arc::ptr_eq(&parent, &d1));
+        assert_eq!(name, "f1");
+        assert!(Arc::ptr_eq(&kid.unwrap(), &f1));
+
+        let (parent, name, kid) = fs.walk_parent(split_path("//dir1/./new/")).unwrap();
+        assert!(Arc::ptr_eq(&parent, &d1));
+        assert_eq!(name, "new");
+        assert!(kid.is_none());
+
+        let err = fs.walk_parent(split_path("/bogus/new")).err();
+        assert_eq!(err, Some(FsError::NotFound));
+        let err = fs.walk_parent(split_path("/dir1/f1/new")).err();
+        assert_eq!(err, Some(FsError::NotADirectory));
+        let err = fs.walk_parent(split_path("/")).err();
+        assert_eq!(err, Some(FsError::NameInvalid));
+        let err = fs.walk_parent(split_path("/dir1/..")).err();
+        assert_eq!(err, Some(FsError::NameInvalid));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut fs = Fs::new();
+        let d1 = fs.new_dir(fs.root(), "dir1").unwrap();
+        let f1 = fs.new_file(d1.clone(), "f1", b"file1 here").unwrap();
+
+        let walk = |fs: &mut Fs, path| fs.walk(split_path(path)).err();
+        assert_eq!(walk(&mut fs, "/bogus"), Some(FsError::NotFound));
+        assert_eq!(
+            walk(&mut fs, "/dir1/f1/bogus"),
+            Some(FsError::NotADirectory)
+        );
+        let err = fs.new_file(f1.clone(), "f2", b"file2 here").err();
+        assert_eq!(err, Some(FsError::NotADirectory));
+        assert_eq!(fs.new_dir(f1, "d").err(), Some(FsError::NotADirectory));
+
+        assert_eq!(FsError::NotFound.errno(), libc::ENOENT);
+        assert_eq!(FsError::NotEmpty.errno(), libc::ENOTEMPTY);
+        assert_eq!(FsError::NotEmpty.to_string(), "directory not empty");
+
+        for code in 1..=22 {
+            let e = FsError::from_code(code).unwrap();
+            assert_eq!(e.code(), code);
+        }
+        assert_eq!(FsError::from_code(0), None);
+        assert_eq!(FsError::from_code(23), None);
+        let e = FsError::Io(io::ErrorKind::NotFound);
+        assert_eq!(
+            FsError::from_code(e.code()),
+            Some(FsError::Io(io::ErrorKind::Other))
+        );
+    }
+
+    #[test]
+    fn test_append() {
+        let mut fs = Fs::new();
+        let f = fs.new_file(fs.root(), "log", b"").unwrap();
+        assert_eq!(fs.append(f.clone(), b"one\n"), Ok(4));
+        assert_eq!(fs.append(f.clone(), b"two\n"), Ok(8));
+        assert_eq!(fs.read_at(f.clone(), 0, 100).unwrap(), b"one\ntwo\n");
+        assert_eq!(fs.getattr(&f).size, 8);
+        assert_eq!(fs.statfs().bytes, 8);
+
+        fs.set_capacity(10);
+        assert_eq!(fs.append(f.clone(), b"three\n"), Err(FsError::NoSpace));
+        assert_eq!(fs.append(f.clone(), b"3\n"), Ok(10));
+        let err = fs.append(fs.root(), b"x").err();
+        assert_eq!(err, Some(FsError::IsADirectory));
+    }
+
+    #[test]
+    fn test_read_at() {
+        let mut fs = Fs::new();
+        let f = fs.new_file(fs.root(), "f", b"HELLO").unwrap();
+        assert_eq!(fs.read_at(f.clone(), 0, 5).unwrap(), b"HELLO");
+        assert_eq!(fs.read_at(f.clone(), 1, 2).unwrap(), b"EL");
+
+        // reads straddling or past the end are cut short.
+        assert_eq!(fs.read_at(f.clone(), 3, 10).unwrap(), b"LO");
+        assert_eq!(fs.read_at(f.clone(), 5, 10).unwrap(), b"");
+        assert_eq!(fs.read_at(f.clone(), 100, 10).unwrap(), b"");
+        assert_eq!(fs.read_at(f, 2, usize::MAX).unwrap(), b"LLO");
+
+        let err = fs.read_at(fs.root(), 0, 10).err();
+        assert_eq!(err, Some(FsError::IsADirectory));
+        let l = fs.symlink(fs.root(), "l", "f").unwrap();
+        assert_eq!(fs.read_at(l, 0, 10).err(), Some(FsError::InvalidArgument));
+    }
+
+    #[test]
+    fn test_file_size() {
+        let mut fs = Fs::new();
+        let f = fs.new_file(fs.root(), "f", b"HELLO").unwrap();
+        let attr = *f.read().unwrap().get_attr();
+        assert_eq!(attr.size, 5);
+        assert_eq!(attr.blocks, 1);
+
+        let big = vec![0u8; 513];
+        let f = fs.new_file(fs.root(), "big", &big).unwrap();
+        let attr = *f.read().unwrap().get_attr();
+        assert_eq!(attr.size, 513);
+        assert_eq!(attr.blocks, 2);
+    }
+
+    #[test]
+    fn test_write_file() {
+        let mut fs = Fs::new();
+        let d = fs.new_dir(fs.root(), "d").unwrap();
+        let f = fs.new_file(d.clone(), "f", b"HELLO").unwrap();
+
+        assert_eq!(fs.write_file(f.clone(), 1, b"ipp"), Ok(3));
+        assert_eq!(f.read().unwrap().to_file().unwrap().data, b"HippO");
+        assert_eq!(fs.write_file(f.clone(), 7, b"!"), Ok(1));
+        assert_eq!(f.read().unwrap().to_file().unwrap().data, b"HippO\0\0!");
+        assert_eq!(f.read().unwrap().get_attr().size, 8);
+
+        assert_eq!(
+            fs.write_file(d.clone(), 0, b"nope"),
+            Err(FsError::IsADirectory)
+        );
+        let l = fs.symlink(d.clone(), "l", "f").unwrap();
+        assert_eq!(fs.write_file(l, 0, b"nope"), Err(FsError::InvalidArgument));
+        let p = fs.mknod(d, "p", FileType::NamedPipe).unwrap();
+        assert_eq!(fs.write_file(p, 0, b"nope"), Err(FsError::InvalidArgument));
+        // a write that would end past the largest offset changes nothing,
+        // and fails before the capacity check sees it.
+        assert_eq!(write_end(usize::MAX, b"x"), Err(FsError::InvalidArgument));
+        assert_eq!(write_end(usize::MAX, b""), Ok(usize::MAX));
+        fs.set_capacity(0);
+        assert_eq!(
+            fs.write_file(f.clone(), usize::MAX, b"x"),
+            Err(FsError::InvalidArgument)
+        );
+        assert_eq!(f.read().unwrap().get_attr().size, 8);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut fs = Fs::new();
+        let d = fs.new_dir(fs.root(), "d").unwrap();
+        let f = fs.new_file(d.clone(), "f", b"HELLO").unwrap();
+        let data = |f: &Kid| f.read().unwrap().to_file().unwrap().data.clone();
+
+        fs.truncate(f.clone(), 8).unwrap();
+        assert_eq!(data(&f), b"HELLO\0\0\0");
+        assert_eq!(f.read().unwrap().get_attr().size, 8);
+
+        fs.truncate(f.clone(), 2).unwrap();
+        assert_eq!(data(&f), b"HE");
+        assert_eq!(f.read().unwrap().get_attr().size, 2);
+
+        assert_eq!(fs.truncate(d, 0), Err(FsError::IsADirectory));
+    }
+
+    #[test]
+    fn test_statfs() {
+        let mut fs = Fs::new();
+        let want = FsStat {
+            inodes: 1,
+            bytes: 0,
+            capacity: DEFAULT_CAPACITY,
+        };
+        assert_eq!(fs.statfs(), want);
+
+        let d = fs.new_dir(fs.root(), "d").unwrap();
+        let f1 = fs.new_file(d.clone(), "f1", b"HELLO").unwrap();
+        let f2 = fs.new_file(d.clone(), "f2", b"").unwrap();
+        fs.write_file(f2.clone(), 0, b"HI").unwrap();
+        assert_eq!(fs.statfs().bytes, 7);
+        assert_eq!(fs.statfs().inodes, 4);
+
+        fs.write_file(f1.clone(), 3, b"LOWORLD").unwrap();
+        assert_eq!(fs.statfs().bytes, 12);
+        fs.truncate(f1, 1).unwrap();
+        assert_eq!(fs.statfs().bytes, 3);
+        fs.rename(d.clone(), "f2", d.clone(), "f1").unwrap();
+        assert_eq!(fs.statfs().bytes, 2);
+        fs.unlink(d, "f1").unwrap();
+        assert_eq!(fs.statfs().bytes, 0);
+        assert_eq!(fs.statfs().inodes, 2);
+
+        fs.set_capacity(100);
+        assert_eq!(fs.statfs().capacity, 100);
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut fs = Fs::new();
+        let f = fs.new_file(fs.root(), "f", b"HELLO").unwrap();
+        fs.link(f.clone(), fs.root(), "g").unwrap();
+        fs.new_dir(fs.root(), "d").unwrap();
+        fs.truncate(f.clone(), 1 << 16).unwrap();
+        fs.truncate(f.clone(), 3).unwrap();
+        let cap = |f: &Kid| f.read().unwrap().to_file().unwrap().data.capacity();
+        let before = cap(&f);
+        assert!(before >= 1 << 16);
+
+        // the hard link doesn't get f counted twice.
+        fs.set_read_only(true);
+        let freed = fs.compact();
+        assert!(cap(&f) < 1 << 16);
+        assert_eq!(freed, before - cap(&f));
+        assert_eq!(fs.read_at(f.clone(), 0, 10).unwrap(), b"HEL");
+        assert_eq!(fs.compact(), 0);
+    }
+
+    #[test]
+    fn test_limits() {
+        let config = FsConfig {
+            max_depth: 3,
+            ..FsConfig::default()
+        };
+        let mut fs = Fs::with_config(config);
+        let c = fs.mkdir_all("/a/b/c").unwrap();
+        fs.new_file(fs.walk(split_path("/a/b")).unwrap(), "f", b"")
+            .unwrap();
+
+        // nothing may go below the deepest level, even where mkdir_all is
+        // making dirs on the way.
+        assert_eq!(
+            fs.new_file(c.clone(), "f", b"").err(),
+            Some(FsError::TooDeep)
+        );
+        assert_eq!(fs.new_dir(c.clone(), "d").err(), Some(FsError::TooDeep));
+        assert_eq!(fs.symlink(c, "l", "/").err(), Some(FsError::TooDeep));
+        assert_eq!(fs.mkdir_all("/x/y/z/w").err(), Some(FsError::TooDeep));
+        assert!(fs.walk(split_path("/x/y/z")).is_ok());
+        assert_eq!(FsError::TooDeep.errno(), libc::ENAMETOOLONG);
+
+        // with no depth allowed, not even the root may have kids.
+        let config = FsConfig {
+            max_depth: 0,
+            ..FsConfig::default()
+        };
+        let mut fs = Fs::with_config(config);
+        assert_eq!(
+            fs.new_file(fs.root(), "f", b"").err(),
+            Some(FsError::TooDeep)
+        );
+        assert_eq!(fs.mkdir_all("/a").err(), Some(FsError::TooDeep));
+        assert_eq!(fs.statfs().inodes, 1);
+        fs.config.max_depth = 1;
+        let a = fs.new_dir(fs.root(), "a").unwrap();
+        assert_eq!(fs.new_dir(a, "b").err(), Some(FsError::TooDeep));
+
+        let config = FsConfig {
+            max_nodes: 4,
+            ..FsConfig::default()
+        };
+        let mut fs = Fs::with_config(config);
+        fs.mkdir_all("/a/b").unwrap();
+        let f = fs.new_file(fs.root(), "f", b"").unwrap();
+        assert_eq!(fs.statfs().inodes, 4);
+
+        // the root counts, so the tree is full.
+        let err = Some(FsError::NoSpace);
+        assert_eq!(fs.new_file(fs.root(), "g", b"").err(), err);
+        assert_eq!(fs.new_dir(fs.root(), "d").err(), err);
+        assert_eq!(fs.mkdir_all("/a/b/c").err(), err);
+        assert_eq!(fs.mknod(fs.root(), "p", FileType::NamedPipe).err(), err);
+        // a hard link adds no node, and removing one makes room.
+        fs.link(f, fs.root(), "g").unwrap();
+        fs.rmdir(fs.walk(split_path("/a")).unwrap(), "b").unwrap();
+        fs.mkdir_all("/a/c").unwrap();
+        assert_eq!(fs.statfs().inodes, 4);
+    }
+
+    #[test]
+    fn test_no_space() {
+        let config = FsConfig {
+            capacity_bytes: 10,
+            ..FsConfig::default()
+        };
+        let mut fs = Fs::with_config(config);
+        let f = fs.new_file(fs.root(), "f", b"HELLO").unwrap();
+        fs.write_file(f.clone(), 5, b"WORL").unwrap();
+        assert_eq!(fs.statfs().bytes, 9);
+
+        // anything that would grow past capacity fails and changes nothing.
+        let err = Some(FsError::NoSpace);
+        assert_eq!(fs.write_file(f.clone(), 5, b"WORLDS").err(), err);
+        assert_eq!(fs.truncate(f.clone(), 11).err(), err);
+        assert_eq!(fs.new_file(fs.root(), "g", b"HI").err(), err);
+        assert_eq!(fs.read_at(f.clone(), 0, 20).unwrap(), b"HELLOWORL");
+        assert_eq!(fs.statfs().bytes, 9);
+        assert_eq!(FsError::NoSpace.errno(), libc::ENOSPC);
+
+        // overwriting in place and filling right up are fine.
+        fs.write_file(f.clone(), 0, b"J").unwrap();
+        fs.write_file(f.clone(), 9, b"D").unwrap();
+        assert_eq!(fs.statfs().bytes, 10);
+
+        // shrinking always works, even over a lowered capacity.
+        fs.set_capacity(4);
+        fs.truncate(f.clone(), 6).unwrap();
+        assert_eq!(fs.write_file(f.clone(), 6, b"!").err(), err);
+        fs.unlink(fs.root(), "f").unwrap();
+        fs.new_file(fs.root(), "g", b"HI").unwrap();
+        assert_eq!(
+            fs.statfs(),
+            FsStat {
+                inodes: 2,
+                bytes: 2,
+                capacity: 4
+            }
+        );
+    }
+
+    #[test]
+    fn test_unlink() {
+        let mut fs = Fs::new();
+        let d = fs.new_dir(fs.root(), "d").unwrap();
+        let f = fs.new_file(d.clone(), "f", b"HELLO").unwrap();
+        fs.new_dir(d.clone(), "sub").unwrap();
+
+        let gone = fs.unlink(d.clone(), "f").unwrap();
+        assert!(Arc::ptr_eq(&gone, &f));
+        assert!(fs.test_walk("/d/f").is_none());
+        assert!(!d.read().unwrap().to_dir().unwrap().kids.contains_key("f"));
+        fs.show_tree();
+
+        assert_eq!(fs.unlink(d.clone(), "f").err(), Some(FsError::NotFound));
+        assert_eq!(
+            fs.unlink(d.clone(), "sub").err(),
+            Some(FsError::IsADirectory)
+        );
+        assert!(fs.test_walk("/d/sub").is_some());
+    }
+
+    #[test]
+    fn test_link() {
+        let mut fs = Fs::new();
+        let d = fs.new_dir(fs.root(), "d").unwrap();
+        let f = fs.new_file(d.clone(), "f", b"HELLO").unwrap();
+        let nlink = |k: &Kid| k.read().unwrap().get_attr().nlink;
+
+        fs.link(f.clone(), d.clone(), "g").unwrap();
+        fs.link(f.clone(), fs.root(), "h").unwrap();
+        assert_eq!(nlink(&f), 3);
+        assert!(Arc::ptr_eq(&fs.walk(split_path("/h")).unwrap(), &f));
+        assert_eq!(fs.statfs().inodes, 3);
+
+        fs.unlink(d.clone(), "f").unwrap();
+        assert_eq!(nlink(&f), 2);
+        // it is still found by the name left in its dir.
+        assert_eq!(fs.path_of(&f).as_deref(), Some("/d/g"));
+        fs.link(f.clone(), fs.root(), "i").unwrap();
+        fs.unlink(d.clone(), "g").unwrap();
+        assert_eq!(
+            fs.path_of(&f).as_deref().map(|p| p == "/h" || p == "/i"),
+            Some(true)
+        );
+        fs.link(f.clone(), d.clone(), "g").unwrap();
+        fs.unlink(fs.root(), "i").unwrap();
+        fs.write_file(f.clone(), 0, b"J").unwrap();
+        let g = fs.walk(split_path("/d/g")).unwrap();
+        assert_eq!(g.read().unwrap().to_file().unwrap().data, b"JELLO");
+
+        // replacing a name by rename drops a link too.
+        let other = fs.new_file(d.clone(), "other", b"").unwrap();
+        fs.rename(d.clone(), "other", d.clone(), "g").unwrap();
+        assert_eq!(nlink(&f), 1);
+        assert!(fs.get(kid_ino(&f)).is_some());
+        fs.unlink(fs.root(), "h").unwrap();
+        assert_eq!(nlink(&f), 0);
+        assert_eq!(fs.statfs().inodes, 3);
+        assert_eq!(fs.statfs().bytes, 0);
+
+        // once forgotten it can't be brought back, even before its inode is reused.
+        assert_eq!(
+            fs.link(f.clone(), d.clone(), "back").err(),
+            Some(FsError::NotFound)
+        );
+        assert_eq!(nlink(&f), 0);
+        let reused = fs.new_file(d.clone(), "new", b"HI").unwrap();
+        assert_eq!(kid_ino(&reused), kid_ino(&f));
+        assert_eq!(
+            fs.link(f.clone(), d.clone(), "back").err(),
+            Some(FsError::NotFound)
+        );
+        assert!(fs.walk(split_path("/d/back")).is_err());
+
+        assert_eq!(
+            fs.link(other.clone(), d.clone(), "g").err(),
+            Some(FsError::AlreadyExists)
+        );
+        assert_eq!(
+            fs.link(d.clone(), fs.root(), "d2").err(),
+            Some(FsError::IsADirectory)
+        );
+    }
+
+    #[test]
+    fn test_dir_nlink() {
+        fn nlink(k: &Kid) -> u32 {
+            k.read().unwrap().get_attr().nlink
+        }
+
+        let mut fs = Fs::new();
+        let d = fs.new_dir(fs.root(), "d").unwrap();
+        let a = fs.new_dir(d.clone(), "a").unwrap();
+        fs.new_dir(d.clone(), "b").unwrap();
+        fs.new_file(d.clone(), "f", b"HELLO").unwrap();
+        assert_eq!(nlink(&d), 4);
+        assert_eq!(nlink(&a), 2);
+
+        // bad counts are put right.
+        d.write().unwrap().get_mut_attr().nlink = 7;
+        a.write().unwrap().get_mut_attr().nlink = 0;
+        fs.root().write().unwrap().get_mut_attr().nlink = 1;
+        fs.recompute_nlink();
+        assert_eq!((nlink(&fs.root()), nlink(&d), nlink(&a)), (3, 4, 2));
+
+        fs.rmdir(d.clone(), "b").unwrap();
+        assert_eq!(nlink(&d), 3);
+        fs.rename(d.clone(), "a", fs.root(), "a").unwrap();
+        assert_eq!((nlink(&fs.root()), nlink(&d)), (4, 2));
+        fs.recompute_nlink();
+        assert_eq!((nlink(&fs.root()), nlink(&d)), (4, 2));
+    }
+
+    #[test]
+    fn test_rmdir() {
+        fn nlink(k: &Kid) -> u32 {
+            k.read().unwrap().get_attr().nlink
+        }
+
+        let mut fs = Fs::new();
+        let root = fs.root();
+        let d = fs.new_dir(root.clone(), "d").unwrap();
+        fs.new_file(d.clone(), "f", b"HELLO").unwrap();
+        assert_eq!(nlink(&root), 3);
+
+        assert_eq!(fs.rmdir(root.clone(), "d").err(), Some(FsError::NotEmpty));
+        assert_eq!(fs.rmdir(d.clone(), "f").err(), Some(FsError::NotADirectory));
+        assert_eq!(
+            fs.rmdir(root.clone(), "bogus").err(),
+            Some(FsError::NotFound)
+        );
+        assert_eq!(nlink(&root), 3);
+
+        fs.unlink(d.clone(), "f").unwrap();
+        let gone = fs.rmdir(root.clone(), "d").unwrap();
+        assert!(Arc::ptr_eq(&gone, &d));
+        assert!(fs.test_walk("/d").is_none());
+        assert_eq!(nlink(&root), 2);
+    }
+
+    #[test]
+    fn test_rename() {
+        fn nlink(k: &Kid) -> u32 {
+            k.read().unwrap().get_attr().nlink
+        }
+
+        let mut fs = Fs::new();
+        let root = fs.root();
+        let d1 = fs.new_dir(root.clone(), "dir1").unwrap();
+        let d2 = fs.new_dir(root.clone(), "dir2").unwrap();
+        let f1 = fs.new_file(d1.clone(), "f1", b"file1 here").unwrap();
+        let sub = fs.new_dir(d1.clone(), "sub").unwrap();
+        fs.new_file(sub.clone(), "f", b"in sub").unwrap();
+
+        // same directory
+        let moved = fs.rename(d1.clone(), "f1", d1.clone(), "g1").unwrap();
+        assert!(Arc::ptr_eq(&moved, &f1));
+        assert!(fs.test_walk("/dir1/f1").is_none());
+        assert!(fs.test_walk("/dir1/g1").is_some());
+
+        // across directories, in both lock orders
+        fs.rename(d1.clone(), "g1", d2.clone(), "f1").unwrap();
+        assert!(Arc::ptr_eq(&fs.test_walk("/dir2/f1").unwrap(), &f1));
+        fs.rename(d2.clone(), "f1", d1.clone(), "f1").unwrap();
+        assert!(Arc::ptr_eq(&fs.test_walk("/dir1/f1").unwrap(), &f1));
+
+        // moving a dir moves its ".." link
+        fs.rename(d1.clone(), "sub", d2.clone(), "sub").unwrap();
+        assert_eq!(nlink(&d1), 2);
+        assert_eq!(nlink(&d2), 3);
+        assert!(fs.test_walk("/dir2/sub/f").is_some());
+
+        // can't replace a non-empty dir, or move a dir under itself
+        let err = fs.rename(root.clone(), "dir1", d2.clone(), "sub").err();
+        assert_eq!(err, Some(FsError::NotEmpty));
+        let err = fs.rename(d2.clone(), "sub", sub.clone(), "loop").err();
+        assert_eq!(err, Some(FsError::InvalidArgument));
+        let err = fs.rename(root.clone(), "dir2", d2.clone(), "loop").err();
+        assert_eq!(err, Some(FsError::InvalidArgument));
+
+        // files can replace files, dirs can replace empty dirs
+        fs.new_file(d1.clone(), "f2", b"file2 here").unwrap();
+        fs.rename(d1.clone(), "f1", d1.clone(), "f2").unwrap();
+        assert!(Arc::ptr_eq(&fs.test_walk("/dir1/f2").unwrap(), &f1));
+        let empty = fs.new_dir(d1.clone(), "empty").unwrap();
+        let empty_ino = fs.getattr(&empty).ino;
+        let err = fs.rename(d1.clone(), "f2", d1.clone(), "empty").err();
+        assert_eq!(err, Some(FsError::IsADirectory));
+        let inodes = fs.statfs().inodes;
+        fs.rename(d2.clone(), "sub", d1.clone(), "empty").unwrap();
+        assert_eq!(nlink(&d1), 3);
+        assert_eq!(nlink(&d2), 2);
+
+        // the replaced dir is gone for good, not just unlinked.
+        assert!(fs.get(empty_ino).is_none());
+        assert_eq!(fs.statfs().inodes, inodes - 1);
+        assert!(empty.read().unwrap().get_parent().is_none());
+    }
+
+    #[test]
+    fn test_inode_index() {
+        fn get_inode(f: &Kid) -> u64 {
+            f.read().unwrap().get_attr().ino
+        }
+
+        let mut fs = Fs::new();
+        let root = fs.root();
+        let d = fs.new_dir(root.clone(), "d").unwrap();
+        let f1 = fs.new_file(d.clone(), "f1", b"file1 here").unwrap();
+        let f2 = fs.new_file(d.clone(), "f2", b"file2 here").unwrap();
+
+        assert!(Arc::ptr_eq(&fs.get(1).unwrap(), &root));
+        for k in [&d, &f1, &f2] {
+            assert!(Arc::ptr_eq(&fs.get(get_inode(k)).unwrap(), k));
+        }
+
+        fs.unlink(d.clone(), "f1").unwrap();
+        assert!(fs.get(get_inode(&f1)).is_none());
+        fs.rename(d.clone(), "f2", root.clone(), "f2").unwrap();
+        assert!(fs.get(get_inode(&f2)).is_some());
+        fs.rmdir(root.clone(), "d").unwrap();
+        assert!(fs.get(get_inode(&d)).is_none());
+    }
+
+    #[test]
+    fn test_chmod() {
+        let mut fs = Fs::new();
+        let f = fs.new_file(fs.root(), "f", b"HELLO").unwrap();
+        let before = *f.read().unwrap().get_attr();
+        assert_eq!(before.perm, FILE_PERM);
+
+        fs.chmod(f.clone(), 0o644).unwrap();
+        let after = *f.read().unwrap().get_attr();
+        assert_eq!(after.perm, 0o644);
+        assert!(after.ctime >= before.ctime);
+        assert_eq!(after.mtime, before.mtime);
+
+        fs.chmod(f.clone(), 0o170755).unwrap();
+        assert_eq!(f.read().unwrap().get_attr().perm, 0o755);
+    }
+
+    #[test]
+    fn test_chown() {
+        let mut fs = Fs::new();
+        let f = fs.new_file(fs.root(), "f", b"HELLO").unwrap();
+        let owner = |f: &Kid| {
+            let attr = *f.read().unwrap().get_attr();
+            (attr.uid, attr.gid)
+        };
+        assert_eq!(owner(&f), (OWNER_UID, OWNER_GID));
+
+        fs.chown(f.clone(), None, Some(1000)).unwrap();
+        assert_eq!(owner(&f), (OWNER_UID, 1000));
+        fs.chown(f.clone(), Some(1001), None).unwrap();
+        assert_eq!(owner(&f), (1001, 1000));
+    }
+
+    #[test]
+    fn test_set_times() {
+        let mut fs = Fs::new();
+        let f = fs.new_file(fs.root(), "f", b"HELLO").unwrap();
+        let before = *f.read().unwrap().get_attr();
+
+        let when = time::UNIX_EPOCH + time::Duration::from_secs(1_000_000_000);
+        fs.set_times(f.clone(), None, Some(when)).unwrap();
+        let after = *f.read().unwrap().get_attr();
+        assert_eq!(after.mtime, when);
+        assert_eq!(after.atime, before.atime);
+        assert!(after.ctime >= before.ctime);
+
+        fs.set_times(f.clone(), Some(when), None).unwrap();
+        assert_eq!(f.read().unwrap().get_attr().atime, when);
+    }
+
+    #[test]
+    fn test_copy_file() {
+        let mut fs = Fs::new();
+        let d1 = fs.new_dir(fs.root(), "dir1").unwrap();
+        let f1 = fs.new_file(d1.clone(), "f1", b"HELLO").unwrap();
+        fs.chmod(f1.clone(), 0o640).unwrap();
+
+        let copy = fs.copy_file(f1.clone(), fs.root(), "copy").unwrap();
+        let ino = |k: &Kid| k.read().unwrap().get_attr().ino;
+        assert_ne!(ino(&copy), ino(&f1));
+        assert!(Arc::ptr_eq(&fs.walk(split_path("/copy")).unwrap(), &copy));
+        assert_eq!(copy.read().unwrap().get_attr().perm, 0o640);
+        assert_eq!(fs.statfs().bytes, 10);
+
+        // the copy doesn't follow later changes to the source.
+        fs.write_file(f1.clone(), 0, b"JELLO").unwrap();
+        fs.chmod(f1.clone(), 0o600).unwrap();
+        let locked = copy.read().unwrap();
+        assert_eq!(locked.to_file().unwrap().data, b"HELLO");
+        assert_eq!(locked.get_attr().perm, 0o640);
+        drop(locked);
+
+        assert_eq!(
+            fs.copy_file(d1, fs.root(), "d").err(),
+            Some(FsError::IsADirectory)
+        );
+    }
+
+    #[test]
+    fn test_fork() {
+        let mut fs = Fs::new();
+        let d = fs.new_dir(fs.root(), "d").unwrap();
+        let f = fs.new_file(d.clone(), "f", b"HELLO").unwrap();
+        fs.link(f.clone(), fs.root(), "hard").unwrap();
+        fs.symlink(fs.root(), "l", "d/f").unwrap();
+        fs.set_xattr(f.clone(), "user.a", b"1").unwrap();
+        let before: Vec<String> = fs.iter().map(|(path, _)| path).collect();
+
+        let snap = fs.fork();
+        assert_eq!(snap.statfs(), fs.statfs());
+        let walk = |fs: &Fs, path| fs.walk(split_path(path)).unwrap();
+        let sf = walk(&snap, "/d/f");
+        assert!(!Arc::ptr_eq(&sf, &f));
+        assert!(Arc::ptr_eq(&sf, &walk(&snap, "/hard")));
+        assert!(Arc::ptr_eq(&snap.get(kid_ino(&f)).unwrap(), &sf));
+        assert_eq!(snap.path_of(&sf).unwrap(), "/d/f");
+
+        // changing the original leaves the copy alone.
+        fs.write_file(f.clone(), 0, b"JELLO").unwrap();
+        fs.set_xattr(f.clone(), "user.a", b"2").unwrap();
+        fs.unlink(fs.root(), "l").unwrap();
+        fs.new_dir(d.clone(), "new").unwrap();
+        fs.chmod(d, 0o700).unwrap();
+        assert_eq!(snap.read_at(sf.clone(), 0, 10).unwrap(), b"HELLO");
+        assert_eq!(snap.get_xattr(sf, "user.a").unwrap(), b"1");
+        let after: Vec<String> = snap.iter().map(|(path, _)| path).collect();
+        assert_eq!(after, before);
+        assert_eq!(walk(&snap, "/d").read().unwrap().get_attr().perm, DIR_PERM);
+
+        // and the other way around.
+        let mut snap = snap;
+        snap.unlink(snap.root(), "hard").unwrap();
+        assert_eq!(f.read().unwrap().get_attr().nlink, 2);
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut fs = Fs::new();
+        let d = fs.new_dir(fs.root(), "d").unwrap();
+        let f = fs.new_file(d.clone(), "f", b"HELLO").unwrap();
+        fs.new_file(d.clone(), "g", b"HI").unwrap();
+        fs.symlink(fs.root(), "l", "d/f").unwrap();
+        let mut snap = fs.fork();
+        assert_eq!(diff(fs.root(), snap.root()), []);
+        assert_eq!(diff(fs.root(), fs.root()), []);
+
+        fs.write_file(f.clone(), 0, b"J").unwrap();
+        assert_eq!(
+            diff(fs.root(), snap.root()),
+            [DiffEntry::Contents("/d/f".to_owned())]
+        );
+
+        fs.chmod(f, 0o600).unwrap();
+        fs.unlink(d.clone(), "g").unwrap();
+        fs.new_dir(d, "new").unwrap();
+        snap.unlink(snap.root(), "l").unwrap();
+        snap.new_dir(snap.root(), "l").unwrap();
+        snap.new_file(snap.root(), "only", b"").unwrap();
+        assert_eq!(
+            diff(fs.root(), snap.root()),
+            [
+                DiffEntry::Contents("/d/f".to_owned()),
+                DiffEntry::Perm("/d/f".to_owned()),
+                DiffEntry::OnlyInB("/d/g".to_owned()),
+                DiffEntry::OnlyInA("/d/new".to_owned()),
+                DiffEntry::Type("/l".to_owned()),
+                DiffEntry::OnlyInB("/only".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_copy_tree() {
+        let mut fs = Fs::new();
+        let d1 = fs.new_dir(fs.root(), "dir1").unwrap();
+        let d2 = fs.new_dir(fs.root(), "dir2").unwrap();
+        let f1 = fs.new_file(d1.clone(), "f1", b"HELLO").unwrap();
+        fs.new_file(d1.clone(), "f2", b"HI").unwrap();
+        fs.symlink(d1.clone(), "link", "f1").unwrap();
+
+        let copy = fs.copy_tree(d1.clone(), d2.clone(), "copy").unwrap();
+        let tree = |fs: &Fs, top: &str| {
+            fs.iter()
+                .filter_map(|(path, kid)| {
+                    let rel = path.strip_prefix(top)?.to_owned();
+                    Some((rel, kid.read().unwrap().to_string()))
+                })
+                .collect::<Vec<_>>()
+        };
+        let want = tree(&fs, "/dir1");
+        let dir1: Vec<(String, String)> = [
+            ("", r#"Dir(["f1", "f2", "link"])"#),
+            ("/f1", "File(HELLO)"),
+            ("/f2", "File(HI)"),
+            ("/link", "Symlink(-> f1)"),
+        ]
+        .iter()
+        .map(|(path, desc)| (path.to_string(), desc.to_string()))
+        .collect();
+        assert_eq!(want, dir1);
+        assert_eq!(tree(&fs, "/dir2/copy"), want);
+        assert_eq!(fs.statfs().inodes, 10);
+
+        // nothing is shared with the source.
+        fs.write_file(f1, 0, b"JELLO").unwrap();
+        fs.new_file(d1.clone(), "f3", b"").unwrap();
+        let f1_copy = fs.walk(split_path("/dir2/copy/f1")).unwrap();
+        assert_eq!(f1_copy.read().unwrap().to_file().unwrap().data, b"HELLO");
+        assert_eq!(fs.readdir(copy).unwrap().len(), 5);
+
+        let err = fs.copy_tree(d1.clone(), d1.clone(), "again").err();
+        assert_eq!(err, Some(FsError::InvalidArgument));
+        fs.set_max_depth(0);
+        let err = fs.copy_tree(d1, d2, "shallow").err();
+        assert_eq!(err, Some(FsError::PathTooLong));
+    }
+
+    #[test]
+    fn test_config() {
+        let config = FsConfig {
+            uid: 1000,
+            gid: 100,
+            dir_perm: 0o755,
+            file_perm: 0o644,
+            read_only: false,
+            case_insensitive: false,
+            normalize_nfc: false,
+            max_name_len: MAX_NAME_LEN,
+            capacity_bytes: DEFAULT_CAPACITY,
+            max_nodes: u64::MAX,
+            max_depth: usize::MAX,
+            attr_ttl: time::Duration::from_millis(250),
+            negative_ttl: time::Duration::ZERO,
+        };
+        let mut fs = Fs::with_config(config);
+        let d = fs.new_dir(fs.root(), "d").unwrap();
+        let f = fs.new_file(d.clone(), "f", b"HELLO").unwrap();
+        let l = fs.symlink(d, "l", "f").unwrap();
+
+        let attr = |k: &Kid| *k.read().unwrap().get_attr();
+        for (kid, perm) in [(fs.root(), 0o755), (f, 0o644), (l, SYMLINK_PERM)] {
+            let attr = attr(&kid);
+            assert_eq!((attr.uid, attr.gid, attr.perm), (1000, 100, perm));
+        }
+
+        assert_eq!(fs.config.attr_ttl, time::Duration::from_millis(250));
+
+        let attr = attr(&Fs::new().root());
+        assert_eq!((attr.uid, attr.gid), (OWNER_UID, OWNER_GID));
+        assert_eq!(attr.perm, DIR_PERM);
+        assert_eq!(Fs::new().config.attr_ttl, time::Duration::from_secs(1));
+
+        let uncached = FsConfig {
+            attr_ttl: time::Duration::ZERO,
+            ..FsConfig::default()
+        };
+        assert_eq!(
+            Fs::with_config(uncached).config.attr_ttl,
+            time::Duration::ZERO
+        );
+        assert_ne!(uncached, FsConfig::default());
+    }
+
+    #[test]
+    fn test_case_insensitive() {
+        let config = FsConfig {
+            case_insensitive: true,
+            ..FsConfig::default()
+        };
+        let mut fs = Fs::with_config(config);
+        let d = fs.new_dir(fs.root(), "Dir").unwrap();
+        let f = fs.new_file(d.clone(), "Foo", b"HELLO").unwrap();
+        let names = |fs: &Fs, d: &Kid| -> Vec<String> {
+            let entries = fs.readdir(d.clone()).unwrap();
+            entries
+                .into_iter()
+                .map(|(name, _, _)| name)
+                .skip(2)
+                .collect()
+        };
+
+        // any casing finds the node, but it keeps the name it was made with.
+        for path in ["/Dir/Foo", "/dir/foo", "/DIR/FOO", "/dIr/fOo"] {
+            assert!(Arc::ptr_eq(&fs.walk(split_path(path)).unwrap(), &f));
+        }
+        assert_eq!(names(&fs, &d), ["Foo"]);
+        assert!(Arc::ptr_eq(&fs.mkdir_all("/DIR").unwrap(), &d));
+
+        // names differing only in case collide.
+        let err = fs.new_file(d.clone(), "FOO", b"HI").err();
+        assert_eq!(err, Some(FsError::AlreadyExists));
+        assert_eq!(fs.new_dir(fs.root(), "dir").err(), err);
+        assert_eq!(fs.symlink(d.clone(), "foo", "x").err(), err);
+        assert_eq!(fs.link(f.clone(), d.clone(), "fOO").err(), err);
+
+        // renames can change just the case, and replace entries of any case.
+        fs.rename(d.clone(), "foo", d.clone(), "FOO").unwrap();
+        assert_eq!(names(&fs, &d), ["FOO"]);
+        let g = fs.new_file(d.clone(), "Bar", b"HI").unwrap();
+        fs.rename(d.clone(), "bar", d.clone(), "foo").unwrap();
+        assert_eq!(names(&fs, &d), ["foo"]);
+        assert!(Arc::ptr_eq(&fs.walk(split_path("/dir/FOO")).unwrap(), &g));
+        assert_eq!(f.read().unwrap().get_attr().nlink, 0);
+
+        fs.unlink(d.clone(), "FOO").unwrap();
+        fs.rmdir(fs.root(), "DIR").unwrap();
+        assert_eq!(names(&fs, &fs.root()), Vec::<String>::new());
+
+        // by default case matters.
+        let mut fs = Fs::new();
+        fs.new_file(fs.root(), "Foo", b"HELLO").unwrap();
+        fs.new_file(fs.root(), "FOO", b"HI").unwrap();
+        assert_eq!(fs.walk(split_path("/foo")).err(), Some(FsError::NotFound));
+    }
+
+    #[test]
+    fn test_generation() {
+        let mut fs = Fs::new();
+        let f = fs.new_file(fs.root(), "f", b"HELLO").unwrap();
+        let ino = fs.getattr(&f).ino;
+        assert_eq!(fs.generation(ino), 0);
+
+        fs.unlink(fs.root(), "f").unwrap();
+        let g = fs.new_file(fs.root(), "g", b"HI").unwrap();
+        assert_eq!(fs.getattr(&g).ino, ino);
+        assert_ne!(fs.generation(ino), 0);
+
+        // every reuse moves it on, and other inodes are left alone.
+        let gen = fs.generation(ino);
+        fs.unlink(fs.root(), "g").unwrap();
+        fs.new_dir(fs.root(), "d").unwrap();
+        assert_ne!(fs.generation(ino), gen);
+        assert_eq!(fs.generation(1), 0);
+        assert_eq!(fs.fork().generation(ino), fs.generation(ino));
+    }
+
+    #[test]
+    fn test_access() {
+        let mut fs = Fs::new();
+        let f = fs.new_file(fs.root(), "f", b"HELLO").unwrap();
+        fs.chown(f.clone(), Some(1000), Some(100)).unwrap();
+        fs.chmod(f.clone(), 0o640).unwrap();
+
+        // the owner may read and write, but not execute.
+        assert!(fs.access(&f, 1000, 100, 0o4));
+        assert!(fs.access(&f, 1000, 1, 0o6));
+        assert!(!fs.access(&f, 1000, 100, 0o1));
+        // the group may read but not write, even with the read bit asked too.
+        assert!(fs.access(&f, 1001, 100, 0o4));
+        assert!(!fs.access(&f, 1001, 100, 0o2));
+        assert!(!fs.access(&f, 1001, 100, 0o6));
+        // everyone else may do nothing but check it's there.
+        assert!(!fs.access(&f, 1001, 101, 0o4));
+        assert!(fs.access(&f, 1001, 101, 0));
+
+        // the owner's bits apply to the owner even when the group's are wider.
+        fs.chmod(f.clone(), 0o070).unwrap();
+        assert!(!fs.access(&f, 1000, 100, 0o4));
+        assert!(fs.access(&f, 1001, 100, 0o7));
+
+        fs.chmod(f.clone(), 0).unwrap();
+        for mask in [0, 0o1, 0o2, 0o4, 0o7] {
+            assert!(fs.access(&f, 0, 0, mask));
+        }
+    }
+
+    #[test]
+    fn test_sticky() {
+        let mut fs = Fs::new();
+        let tmp = fs.new_dir(fs.root(), "tmp").unwrap();
+        fs.chown(tmp.clone(), Some(0), None).unwrap();
+        fs.chmod(tmp.clone(), 0o1777).unwrap();
+        assert_eq!(fs.getattr(&tmp).perm, 0o1777);
+        for (name, uid) in [("a", 1000), ("b", 1000), ("c", 1000), ("d", 1000)] {
+            let f = fs.new_file(tmp.clone(), name, b"HELLO").unwrap();
+            fs.chown(f, Some(uid), None).unwrap();
+        }
+        let sub = fs.new_dir(tmp.clone(), "sub").unwrap();
+        fs.chown(sub, Some(1000), None).unwrap();
+
+        // someone else can't remove another user's entries.
+        let denied = Some(FsError::PermissionDenied);
+        assert_eq!(
+            fs.unlink_checked(tmp.clone(), "a", Some(1001)).err(),
+            denied
+        );
+        assert_eq!(
+            fs.rmdir_checked(tmp.clone(), "sub", Some(1001)).err(),
+            denied
+        );
+        assert!(fs.walk(split_path("/tmp/a")).is_ok());
+        assert!(fs.walk(split_path("/tmp/sub")).is_ok());
+
+        // but the owner, the dir's owner and root can.
+        assert!(fs.unlink_checked(tmp.clone(), "a", Some(1000)).is_ok());
+        assert!(fs.rmdir_checked(tmp.clone(), "sub", Some(1000)).is_ok());
+        assert!(fs.unlink_checked(tmp.clone(), "b", None).is_ok());
+        assert!(fs.unlink_checked(tmp.clone(), "c", Some(0)).is_ok());
+        fs.chown(tmp.clone(), Some(1001), None).unwrap();
+        assert!(fs.unlink_checked(tmp.clone(), "d", Some(1001)).is_ok());
+
+        // without the sticky bit anyone may.
+        let f = fs.new_file(tmp.clone(), "e", b"HELLO").unwrap();
+        fs.chown(f, Some(1000), None).unwrap();
+        fs.chmod(tmp.clone(), 0o777).unwrap();
+        assert!(fs.unlink_checked(tmp, "e", Some(1002)).is_ok());
+    }
+
+    #[test]
+    fn test_walk_checked() {
+        let mut fs = Fs::new();
+        let d = fs.new_dir(fs.root(), "d").unwrap();
+        let e = fs.new_dir(d.clone(), "e").unwrap();
+        fs.new_file(e.clone(), "f", b"HELLO").unwrap();
+        for kid in [fs.root(), d.clone(), e.clone()] {
+            fs.chmod(kid, 0o755).unwrap();
+        }
+        fs.chown(d.clone(), Some(1000), Some(100)).unwrap();
+        let user = Some((1000, 100));
+        let path = || split_path("/d/e/f");
+        assert!(fs.walk_checked(path(), user).is_ok());
+
+        // without execute on d, nothing under it can be reached, though d
+        // itself still can.
+        fs.chmod(d.clone(), 0o655).unwrap();
+        assert_eq!(
+            fs.walk_checked(path(), user).err(),
+            Some(FsError::PermissionDenied)
+        );
+        assert_eq!(
+            fs.walk_checked(split_path("/d/."), user).err(),
+            Some(FsError::PermissionDenied)
+        );
+        assert!(fs.walk_checked(split_path("/d"), user).is_ok());
+        // others still get in by d's other bits, and root always does.
+        assert!(fs.walk_checked(path(), Some((1001, 101))).is_ok());
+        assert!(fs.walk_checked(path(), Some((0, 0))).is_ok());
+        assert!(fs.walk_checked(path(), None).is_ok());
+        assert!(fs.walk(path()).is_ok());
+
+        fs.chmod(d, 0o755).unwrap();
+        fs.chown(e.clone(), Some(1000), Some(100)).unwrap();
+        fs.chmod(e, 0o754).unwrap();
+        assert!(fs.walk_checked(path(), user).is_ok());
+        assert_eq!(
+            fs.walk_checked(path(), Some((1001, 101))).err(),
+            Some(FsError::PermissionDenied)
+        );
+        assert_eq!(FsError::PermissionDenied.errno(), libc::EACCES);
+    }
+
+    struct Capture;
+
+    static CAPTURED: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+
+    impl log::Log for Capture {
+        fn enabled(&self, _: &log::Metadata<'_>) -> bool {
+            true
+        }
+        fn log(&self, record: &log::Record<'_>) {
+            CAPTURED.lock().unwrap().push(record.args().to_string());
+        }
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn test_logging() {
+        static LOGGER: Capture = Capture;
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Debug);
+
+        let mut fs = Fs::new();
+        let d = fs.new_dir(fs.root(), "logged_d").unwrap();
+        let f = fs.new_file(d.clone(), "logged_f", b"HELLO").unwrap();
+        fs.rename(d.clone(), "logged_f", fs.root(), "logged_g")
+            .unwrap();
+        fs.unlink(fs.root(), "logged_g").unwrap();
+        fs.rmdir(fs.root(), "logged_x").unwrap_err();
+        let (d, f) = (kid_ino(&d), kid_ino(&f));
+
+        // other tests log too, so only look for this one's names.
+        let got: Vec<String> = CAPTURED
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|msg| msg.contains(" 1/logged_") || msg.contains("/logged_f"))
+            .cloned()
+            .collect();
+        let want = [
+            format!("new_dir 1/logged_d: ino {d}"),
+            format!("new_file {d}/logged_f: ino {f}"),
+            format!("rename {d}/logged_f -> 1/logged_g: ino {f}"),
+            format!("unlink 1/logged_g: ino {f}"),
+            "rmdir 1/logged_x: not found".to_owned(),
+        ];
+        assert_eq!(got, want);
+    }
+
+    #[test]
+    fn test_with_dir() {
+        let mut fs = Fs::new();
+        let d = fs.new_dir(fs.root(), "d").unwrap();
+        let f = fs.new_file(d.clone(), "f", b"HELLO").unwrap();
+        assert_eq!(with_dir(&d, |dir| dir.kids.len()), Some(1));
+        assert_eq!(with_dir(&f, |dir| dir.kids.len()), None);
+
+        // the lock is gone once it returns.
+        with_dir(&d, |dir| dir.attr.perm = 0o700);
+        assert_eq!(fs.getattr(&d).perm, 0o700);
+
+        // creating still checks the parent, then the name.
+        let root = fs.root();
+        assert_eq!(
+            fs.new_file(f.clone(), "x", b"").err(),
+            Some(FsError::NotADirectory)
+        );
+        assert_eq!(
+            fs.new_dir(f.clone(), "x").err(),
+            Some(FsError::NotADirectory)
+        );
+        assert_eq!(
+            fs.new_dir(root.clone(), "d").err(),
+            Some(FsError::AlreadyExists)
+        );
+        let sub = fs.new_dir(d.clone(), "sub").unwrap();
+        assert_eq!(fs.getattr(&d).nlink, 3);
+        assert!(Arc::ptr_eq(&sub.read().unwrap().get_parent().unwrap(), &d));
+        assert!(Arc::ptr_eq(&fs.get(fs.getattr(&sub).ino).unwrap(), &sub));
+        assert_eq!(fs.path_of(&f).as_deref(), Some("/d/f"));
+        assert_eq!(fs.statfs().bytes, 5);
+    }
+
+    #[test]
+    fn test_mknod() {
+        let mut fs = Fs::new();
+        let d = fs.new_dir(fs.root(), "d").unwrap();
+        let fifo = fs.mknod(d.clone(), "fifo", FileType::NamedPipe).unwrap();
+        fs.mknod(d.clone(), "sock", FileType::Socket).unwrap();
+        let ino = fifo.read().unwrap().get_attr().ino;
+
+        let entries = fs.readdir(d.clone()).unwrap();
+        assert_eq!(entries[2], ("fifo".to_owned(), FileType::NamedPipe, ino));
+        assert_eq!(entries[3].1, FileType::Socket);
+        let attr = fs.getattr(&fifo);
+        assert_eq!((attr.size, attr.nlink, attr.perm), (0, 1, FILE_PERM));
+        assert_eq!(fifo.read().unwrap().to_string(), "Special(NamedPipe)");
+
+        // there's nothing to read or write.
+        assert_eq!(
+            fs.read_at(fifo.clone(), 0, 10).err(),
+            Some(FsError::InvalidArgument)
+        );
+        assert_eq!(
+            fs.append(fifo.clone(), b"x").err(),
+            Some(FsError::InvalidArgument)
+        );
+        for kind in [
+            FileType::RegularFile,
+            FileType::Directory,
+            FileType::Symlink,
+        ] {
+            assert_eq!(
+                fs.mknod(d.clone(), "bad", kind).err(),
+                Some(FsError::InvalidArgument)
+            );
+        }
+        assert_eq!(
+            fs.mknod(d.clone(), "fifo", FileType::Socket).err(),
+            Some(FsError::AlreadyExists)
+        );
+
+        // copies and forks keep the kind.
+        let copy = fs.copy_tree(d.clone(), fs.root(), "copy").unwrap();
+        let forked = fs.fork();
+        for (fs, path) in [(&fs, "/copy/fifo"), (&forked, "/d/fifo")] {
+            let kid = fs.walk(split_path(path)).unwrap();
+            assert_eq!(kid.read().unwrap().get_attr().kind, FileType::NamedPipe);
+        }
+        assert_eq!(fs.readdir(copy).unwrap().len(), 4);
+        fs.unlink(d, "fifo").unwrap();
+        assert!(fs.get(ino).is_none());
+    }
+
+    #[test]
+    fn test_mknod_dev() {
+        let mut fs = Fs::new();
+        let d = fs.new_dir(fs.root(), "dev").unwrap();
+        let null = fs
+            .mknod_dev(d.clone(), "null", FileType::CharDevice, 0x103)
+            .unwrap();
+        let sda = fs
+            .mknod_dev(d.clone(), "sda", FileType::BlockDevice, 0x800)
+            .unwrap();
+        assert_eq!(null.read().unwrap().get_attr().rdev, 0x103);
+        assert_eq!(null.read().unwrap().get_attr().kind, FileType::CharDevice);
+        assert_eq!(sda.read().unwrap().get_attr().rdev, 0x800);
+        assert_eq!(fs.readdir(d.clone()).unwrap()[3].1, FileType::BlockDevice);
+        assert_eq!(
+            fs.mknod_dev(d.clone(), "p", FileType::NamedPipe, 1).err(),
+            Some(FsError::InvalidArgument)
+        );
+        assert_eq!(
+            fs.mknod(d.clone(), "c", FileType::CharDevice).err(),
+            Some(FsError::InvalidArgument)
+        );
+
+        // the device number goes with copies.
+        fs.copy_tree(d, fs.root(), "copy").unwrap();
+        let copy = fs.walk(split_path("/copy/null")).unwrap();
+        assert_eq!(fs.getattr(&copy).rdev, 0x103);
+    }
+
+    #[test]
+    fn test_create_exists() {
+        let mut fs = Fs::new();
+        let d = fs.new_dir(fs.root(), "d").unwrap();
+        let f = fs.new_file(d.clone(), "f", b"HELLO").unwrap();
+        let ino = f.read().unwrap().get_attr().ino;
+        for res in [
+            fs.new_file(d.clone(), "f", b"HI"),
+            fs.new_dir(d.clone(), "f"),
+            fs.symlink(d.clone(), "f", "x"),
+            fs.new_file(fs.root(), "d", b""),
+        ] {
+            assert_eq!(res.err(), Some(FsError::AlreadyExists));
+        }
+        assert_eq!(fs.read_at(f.clone(), 0, 10).unwrap(), b"HELLO");
+        assert_eq!(fs.statfs().bytes, 5);
+
+        // replacing frees the old inode for reuse.
+        let g = fs.create_or_replace(d.clone(), "f", b"HI").unwrap();
+        assert_eq!(g.read().unwrap().get_attr().ino, ino);
+        assert_eq!(f.read().unwrap().get_attr().nlink, 0);
+        assert_eq!(fs.read_at(g, 0, 10).unwrap(), b"HI");
+        assert_eq!(fs.statfs().bytes, 2);
+        fs.create_or_replace(d.clone(), "new", b"").unwrap();
+        assert_eq!(
+            fs.create_or_replace(fs.root(), "d", b"").err(),
+            Some(FsError::IsADirectory)
+        );
+    }
+
+    #[test]
+    fn test_normalize_nfc() {
+        let config = FsConfig {
+            normalize_nfc: true,
+            ..FsConfig::default()
+        };
+        let mut fs = Fs::with_config(config);
+        let (nfd, nfc) = ("cafe\u{301}", "caf\u{e9}");
+        let f = fs.new_file(fs.root(), nfd, b"HELLO").unwrap();
+
+        // the name is stored in NFC and either form finds it.
+        let entries = fs.readdir(fs.root()).unwrap();
+        assert_eq!(entries[2].0, nfc);
+        for path in [nfc, nfd] {
+            assert!(Arc::ptr_eq(&fs.walk(split_path(path)).unwrap(), &f));
+        }
+        assert_eq!(
+            fs.new_file(fs.root(), nfc, b"HI").err(),
+            Some(FsError::AlreadyExists)
+        );
+        let g = fs.create_or_replace(fs.root(), nfc, b"HI").unwrap();
+        assert_eq!(f.read().unwrap().get_attr().nlink, 0);
+        assert_eq!(fs.readdir(fs.root()).unwrap().len(), 3);
+        fs.unlink(fs.root(), nfd).unwrap();
+        assert_eq!(g.read().unwrap().get_attr().nlink, 0);
+
+        // canonically equivalent names with marks in another order, or
+        // outside latin, are one name too.
+        let d = fs.new_dir(fs.root(), "Vie\u{323}\u{302}t").unwrap();
+        assert!(Arc::ptr_eq(
+            &fs.walk(split_path("Vi\u{1ec7}t")).unwrap(),
+            &d
+        ));
+        assert!(Arc::ptr_eq(
+            &fs.walk(split_path("Vie\u{302}\u{323}t")).unwrap(),
+            &d
+        ));
+        fs.new_dir(fs.root(), "\u{1112}\u{1161}\u{11ab}").unwrap();
+        assert_eq!(
+            fs.new_dir(fs.root(), "\u{d55c}").err(),
+            Some(FsError::AlreadyExists)
+        );
+
+        // by default the two forms are different names.
+        let mut fs = Fs::new();
+        fs.new_file(fs.root(), nfd, b"HELLO").unwrap();
+        assert_eq!(fs.walk(split_path(nfc)).err(), Some(FsError::NotFound));
+    }
+
+    #[test]
+    fn test_read_only() {
+        let config = FsConfig {
+            read_only: true,
+            ..FsConfig::default()
+        };
+        let mut fs = Fs::with_config(config);
+        let err = fs.new_file(fs.root(), "f", b"HELLO").err();
+        assert_eq!(err, Some(FsError::ReadOnly));
+        assert_eq!(FsError::ReadOnly.errno(), libc::EROFS);
+        assert_eq!(fs.statfs().inodes, 1);
+
+        // a populated tree can be frozen afterwards.
+        fs.set_read_only(false);
+        let f = fs.new_file(fs.root(), "f", b"HELLO").unwrap();
+        fs.set_read_only(true);
+        let checks = [
+            fs.write_file(f.clone(), 0, b"J").err(),
+            fs.truncate(f.clone(), 0).err(),
+            fs.chmod(f.clone(), 0o777).err(),
+            fs.set_xattr(f.clone(), "user.comment", b"hi").err(),
+            fs.new_dir(fs.root(), "d").err(),
+            fs.rename(fs.root(), "f", fs.root(), "g").err(),
+            fs.unlink(fs.root(), "f").err(),
+        ];
+        assert_eq!(checks, [Some(FsError::ReadOnly); 7]);
+        assert_eq!(f.read().unwrap().to_file().unwrap().data, b"HELLO");
+        assert!(fs.walk(split_path("/f")).is_ok());
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let mut fs = Fs::new();
+        let d1 = fs.new_dir(fs.root(), "dir1").unwrap();
+        let events = fs.subscribe();
+
+        let f = fs.new_file(d1.clone(), "f1", b"HELLO").unwrap();
+        let ino = kid_ino(&f);
+        let path = "/dir1/f1".to_owned();
+        let created = FsEvent::Created {
+            ino,
+            parent: kid_ino(&d1),
+            path,
+        };
+        assert_eq!(events.try_recv(), Ok(created));
+
+        fs.write_file(f.clone(), 0, b"J").unwrap();
+        fs.rename(d1.clone(), "f1", fs.root(), "f2").unwrap();
+        fs.unlink(fs.root(), "f2").unwrap();
+        let want = [
+            FsEvent::Modified { ino },
+            FsEvent::Renamed {
+                from_parent: kid_ino(&d1),
+                from: "/dir1/f1".to_owned(),
+                to_parent: 1,
+                to: "/f2".to_owned(),
+            },
+            FsEvent::Removed {
+                ino,
+                parent: 1,
+                name: "f2".to_owned(),
+            },
+        ];
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), want);
+
+        // each name a node loses is a removal, even while others remain,
+        // and so is a dir's.
+        let g = fs.new_file(d1.clone(), "g", b"").unwrap();
+        fs.link(g.clone(), fs.root(), "h").unwrap();
+        let e = fs.new_dir(fs.root(), "e").unwrap();
+        fs.unlink(d1.clone(), "g").unwrap();
+        fs.rmdir(fs.root(), "e").unwrap();
+        let removed = |ino, parent, name: &str| FsEvent::Removed {
+            ino,
+            parent,
+            name: name.to_owned(),
+        };
+        let want = [
+            removed(kid_ino(&g), kid_ino(&d1), "g"),
+            removed(kid_ino(&e), 1, "e"),
+        ];
+        let got: Vec<_> = events
+            .try_iter()
+            .filter(|e| matches!(e, FsEvent::Removed { .. }))
+            .collect();
+        assert_eq!(got, want);
+
+        // a dropped receiver just stops the events.
+        drop(events);
+        fs.new_file(d1, "f3", b"").unwrap();
+        assert!(fs.events.is_none());
+    }
+
+    #[test]
+    fn test_xattr() {
+        let mut fs = Fs::new();
+        let f = fs.new_file(fs.root(), "f", b"HELLO").unwrap();
+        let l = fs.symlink(fs.root(), "l", "f").unwrap();
+
+        fs.set_xattr(f.clone(), "user.comment", b"hi").unwrap();
+        assert_eq!(fs.get_xattr(f.clone(), "user.comment").unwrap(), b"hi");
+        assert_eq!(fs.list_xattr(f.clone()).unwrap(), ["user.comment"]);
+        fs.remove_xattr(f.clone(), "user.comment").unwrap();
+        assert!(fs.list_xattr(f.clone()).unwrap().is_empty());
+
+        assert_eq!(
+            fs.get_xattr(f.clone(), "user.comment").err(),
+            Some(FsError::NoXattr)
+        );
+        assert_eq!(
+            fs.remove_xattr(f.clone(), "user.comment").err(),
+            Some(FsError::NoXattr)
+        );
+        let big = vec![0; MAX_XATTR_SIZE + 1];
+        assert_eq!(
+            fs.set_xattr(f.clone(), "user.big", &big).err(),
+            Some(FsError::XattrTooBig)
+        );
+        assert_eq!(
+            fs.set_xattr(l, "user.comment", b"hi").err(),
+            Some(FsError::NotSupported)
+        );
+    }
+
+    #[test]
+    fn test_inode_reuse() {
+        fn get_inode(f: &Kid) -> u64 {
+            f.read().unwrap().get_attr().ino
+        }
+
+        let mut fs = Fs::new();
+        let root = fs.root();
+        let f1 = fs.new_file(root.clone(), "f1", b"file1 here").unwrap();
+        let ino = get_inode(&f1);
+        fs.unlink(root.clone(), "f1").unwrap();
+        let f2 = fs.new_file(root.clone(), "f2", b"file2 here").unwrap();
+        assert_eq!(get_inode(&f2), ino);
+        assert!(Arc::ptr_eq(&fs.get(ino).unwrap(), &f2));
+
+        let d = fs.new_dir(root.clone(), "d").unwrap();
+        let ino = get_inode(&d);
+        fs.rmdir(root.clone(), "d").unwrap();
+        assert_eq!(get_inode(&fs.new_dir(root.clone(), "d").unwrap()), ino);
+        assert_ne!(get_inode(&fs.new_dir(root, "e").unwrap()), ino);
+    }
+
+    #[test]
+    fn test_readdir() {
+        let mut fs = Fs::new();
+        let d1 = fs.new_dir(fs.root(), "dir1").unwrap();
+        let f2 = fs.new_file(d1.clone(), "f2", b"file2 here").unwrap();
+        let f1 = fs.new_file(d1.clone(), "f1", b"file1 here").unwrap();
+        let sub = fs.new_dir(d1.clone(), "a_sub").unwrap();
+        let ino = |k: &Kid| k.read().unwrap().get_attr().ino;
+
+        let want = vec![
+            (".".to_owned(), FileType::Directory, ino(&d1)),
+            ("..".to_owned(), FileType::Directory, 1),
+            ("a_sub".to_owned(), FileType::Directory, ino(&sub)),
+            ("f1".to_owned(), FileType::RegularFile, ino(&f1)),
+            ("f2".to_owned(), FileType::RegularFile, ino(&f2)),
+        ];
+        assert_eq!(fs.readdir(d1), Ok(want));
+        assert_eq!(fs.readdir(f1), Err(FsError::NotADirectory));
+
+        let root_entries = fs.readdir(fs.root()).unwrap();
+        assert_eq!(root_entries[1], ("..".to_owned(), FileType::Directory, 1));
+    }
+
+    #[test]
+    fn test_find() {
+        let mut fs = Fs::new();
+        let d1 = fs.new_dir(fs.root(), "dir1").unwrap();
+        let d2 = fs.new_dir(fs.root(), "dir2").unwrap();
+        let f1 = fs.new_file(d1.clone(), "f1", b"HELLO").unwrap();
+        fs.new_file(d1.clone(), "f2", b"HI").unwrap();
+        let f3 = fs.new_file(d2.clone(), "f3", b"HEY").unwrap();
+
+        let big = fs.find(|e| e.to_file().is_some_and(|f| f.data.len() > 2));
+        let paths: Vec<&str> = big.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, ["/dir1/f1", "/dir2/f3"]);
+        assert!(Arc::ptr_eq(&big[0].1, &f1));
+        assert!(Arc::ptr_eq(&big[1].1, &f3));
+
+        // the predicate can take locks of its own.
+        let d2_ino = d2.read().unwrap().get_attr().ino;
+        let under_d2 = fs.find(|e| match e.get_parent() {
+            Some(parent) => parent.read().unwrap().get_attr().ino == d2_ino,
+            None => false,
+        });
+        assert_eq!(under_d2.len(), 1);
+        assert!(fs.find(|_| false).is_empty());
+    }
+
+    #[test]
+    fn test_readdir_sorted() {
+        let mut fs = Fs::new();
+        let d = fs.new_dir(fs.root(), "d").unwrap();
+        let mut names: Vec<String> = (0..50).map(|i| format!("n{}", (i * 37) % 50)).collect();
+        for name in &names {
+            fs.new_file(d.clone(), name, b"").unwrap();
+        }
+        names.sort();
+
+        let got: Vec<String> = fs
+            .readdir(d.clone())
+            .unwrap()
+            .into_iter()
+            .skip(2)
+            .map(|(name, _, _)| name)
+            .collect();
+        assert_eq!(got, names);
+        assert_eq!(d.read().unwrap().to_string(), format!("Dir({:?})", names));
+    }
+
+    #[test]
+    fn test_path_of() {
+        let mut fs = Fs::new();
+        let root = fs.root();
+        let d1 = fs.new_dir(root.clone(), "dir1").unwrap();
+        let d2 = fs.new_dir(root.clone(), "dir2").unwrap();
+        let f1 = fs.new_file(d1.clone(), "f1", b"file1 here").unwrap();
+        let link = fs.symlink(d1.clone(), "link", "f1").unwrap();
+
+        assert_eq!(fs.path_of(&root).as_deref(), Some("/"));
+        assert_eq!(fs.path_of(&d1).as_deref(), Some("/dir1"));
+        assert_eq!(fs.path_of(&f1).as_deref(), Some("/dir1/f1"));
+        assert_eq!(fs.path_of(&link).as_deref(), Some("/dir1/link"));
+
+        fs.rename(d1.clone(), "f1", d2.clone(), "g1").unwrap();
+        assert_eq!(fs.path_of(&f1).as_deref(), Some("/dir2/g1"));
+        fs.rename(root.clone(), "dir1", d2.clone(), "d").unwrap();
+        assert_eq!(fs.path_of(&link).as_deref(), Some("/dir2/d/link"));
+
+        fs.unlink(d2.clone(), "g1").unwrap();
+        assert_eq!(fs.path_of(&f1), None);
+        fs.unlink(d1.clone(), "link").unwrap();
+        fs.rmdir(d2.clone(), "d").unwrap();
+        assert_eq!(fs.path_of(&d1), None);
+    }
 }
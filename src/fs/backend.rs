@@ -0,0 +1,74 @@
+// where file data ends up. the tree itself always lives in memory, and a
+// backend is told when a caller wants a node's data made durable.
+
+use super::{kid_ino, Fs, FsError, Kid};
+use std::fmt;
+use std::sync::Arc;
+
+// Backend is the extension point for storing data somewhere other than memory.
+pub trait Backend: fmt::Debug + Send + Sync {
+    // fsync makes kid's data durable before returning.
+    fn fsync(&mut self, kid: &Kid) -> Result<(), FsError>;
+}
+
+// MemoryBackend keeps nothing anywhere else, so there is nothing to flush.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MemoryBackend;
+
+impl Backend for MemoryBackend {
+    fn fsync(&mut self, _kid: &Kid) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+impl Fs {
+    // set_backend replaces the backend, which is a MemoryBackend to start.
+    pub fn set_backend(&mut self, backend: Box<dyn Backend>) {
+        self.backend = backend;
+    }
+
+    // fsync flushes kid through the backend. kid must still be in the tree.
+    pub fn fsync(&mut self, kid: Kid) -> Result<(), FsError> {
+        match self.inodes.get(&kid_ino(&kid)) {
+            Some(live) if Arc::ptr_eq(live, &kid) => self.backend.fsync(&kid),
+            _ => Err(FsError::NotFound),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fs::*;
+    use std::sync::Mutex;
+
+    // CountingBackend records the inode of every node it is asked to flush.
+    #[derive(Debug, Default)]
+    struct CountingBackend(Arc<Mutex<Vec<u64>>>);
+
+    impl Backend for CountingBackend {
+        fn fsync(&mut self, kid: &Kid) -> Result<(), FsError> {
+            let ino = kid.read().unwrap().get_attr().ino;
+            self.0.lock().unwrap().push(ino);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_fsync() {
+        let mut fs = Fs::new();
+        let f = fs.new_file(fs.root(), "f", b"HELLO").unwrap();
+        assert_eq!(fs.fsync(f.clone()), Ok(()));
+        assert_eq!(fs.fsync(fs.root()), Ok(()));
+
+        let synced = Arc::new(Mutex::new(Vec::new()));
+        fs.set_backend(Box::new(CountingBackend(synced.clone())));
+        fs.fsync(f.clone()).unwrap();
+        let ino = f.read().unwrap().get_attr().ino;
+        assert_eq!(*synced.lock().unwrap(), [ino]);
+
+        // a node no longer in the tree can't be flushed.
+        fs.unlink(fs.root(), "f").unwrap();
+        assert_eq!(fs.fsync(f), Err(FsError::NotFound));
+        assert_eq!(synced.lock().unwrap().len(), 1);
+    }
+}
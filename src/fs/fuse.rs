@@ -0,0 +1,473 @@
+use super::{new_attr, split_path, Fs, FsError, FsEvent, Kid};
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, Notifier, ReplyAttr, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyXattr, Request, Session, TimeOrNow,
+};
+use std::ffi::OsStr;
+use std::io;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// Invalidation is a kernel cache entry made stale by a change, as fuser's
+// Notifier takes it: an inode's attrs and data, or a name in a dir.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Invalidation {
+    Inode(u64),
+    Entry { parent: u64, name: String },
+}
+
+impl FsEvent {
+    // invalidations gives what a mount should drop from its caches after
+    // the event. Created clears a cached miss for the new name.
+    pub fn invalidations(&self) -> Vec<Invalidation> {
+        let entry = |parent: u64, path: &str| {
+            let name = split_path(path).pop()?;
+            Some(Invalidation::Entry { parent, name })
+        };
+        match self {
+            FsEvent::Modified { ino } => vec![Invalidation::Inode(*ino)],
+            FsEvent::Removed { ino, parent, name } => vec![
+                Invalidation::Entry {
+                    parent: *parent,
+                    name: name.clone(),
+                },
+                Invalidation::Inode(*ino),
+            ],
+            FsEvent::Created { parent, path, .. } => entry(*parent, path).into_iter().collect(),
+            FsEvent::Renamed {
+                from_parent,
+                from,
+                to_parent,
+                to,
+            } => [(*from_parent, from), (*to_parent, to)]
+                .into_iter()
+                .filter_map(|(parent, path)| entry(parent, path))
+                .collect(),
+        }
+    }
+}
+
+// send_invalidations passes each invalidation for events to the kernel,
+// until the Fs sending them is dropped. an entry the kernel never cached
+// comes back as ENOENT, which is nothing to act on.
+fn send_invalidations(events: mpsc::Receiver<FsEvent>, notifier: Notifier) {
+    for event in events {
+        for inval in event.invalidations() {
+            let res = match &inval {
+                Invalidation::Inode(ino) => notifier.inval_inode(*ino, 0, 0),
+                Invalidation::Entry { parent, name } => {
+                    notifier.inval_entry(*parent, OsStr::new(name))
+                }
+            };
+            match res {
+                Err(e) if e.raw_os_error() != Some(libc::ENOENT) => {
+                    log::warn!("invalidate {:?}: {}", inval, e)
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Fs {
+    // mount serves the filesystem at mountpoint until it is unmounted.
+    // a read-only fs is always mounted read-only. the mount takes over the
+    // subscription, so each change is dropped from the kernel's caches.
+    pub fn mount(mut self, mountpoint: &Path, options: &[MountOption]) -> io::Result<()> {
+        let mut options = options.to_vec();
+        if self.read_only() && !options.contains(&MountOption::RO) {
+            options.push(MountOption::RO);
+        }
+        let events = self.subscribe();
+        let mut session = Session::new(self, mountpoint, &options)?;
+        let notifier = session.notifier();
+        thread::spawn(move || send_invalidations(events, notifier));
+        session.run()
+    }
+
+    fn get_or_enoent(&self, ino: u64) -> Result<Kid, FsError> {
+        self.get(ino).ok_or(FsError::NotFound)
+    }
+
+    fn lookup_kid(&self, parent: u64, name: &OsStr) -> Result<Kid, FsError> {
+        let parent = self.get_or_enoent(parent)?;
+        let locked = parent.read().unwrap();
+        let dir = locked.to_dir().ok_or(FsError::NotADirectory)?;
+        let name = name.to_str().ok_or(FsError::NotFound)?;
+        let (_, kid) = self.find_kid(dir, name).ok_or(FsError::NotFound)?;
+        Ok(kid.clone())
+    }
+
+    // negative_entry is the reply that lets the kernel cache a failed lookup:
+    // an entry with inode 0, kept for negative_ttl. None if misses aren't cached.
+    fn negative_entry(&self) -> Option<(Duration, FileAttr)> {
+        let ttl = self.config.negative_ttl;
+        if ttl.is_zero() {
+            return None;
+        }
+        Some((ttl, new_attr(&self.config, 0, FileType::RegularFile, 0)))
+    }
+}
+
+// reply_xattr answers with data's size when size is 0, else with data if it fits.
+fn reply_xattr(size: u32, data: &[u8], reply: ReplyXattr) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+fn to_time(t: TimeOrNow) -> SystemTime {
+    match t {
+        TimeOrNow::SpecificTime(t) => t,
+        TimeOrNow::Now => SystemTime::now(),
+    }
+}
+
+impl Filesystem for Fs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.lookup_kid(parent, name) {
+            Ok(kid) => {
+                let attr = Fs::getattr(self, &kid);
+                reply.entry(&self.config.attr_ttl, &attr, self.generation(attr.ino))
+            }
+            Err(FsError::NotFound) => match self.negative_entry() {
+                Some((ttl, attr)) => reply.entry(&ttl, &attr, 0),
+                None => reply.error(FsError::NotFound.errno()),
+            },
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.get_or_enoent(ino) {
+            Ok(kid) => reply.attr(&self.config.attr_ttl, &Fs::getattr(self, &kid)),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let res = self.get_or_enoent(ino).and_then(|kid| {
+            if let Some(size) = size {
+                self.truncate(kid.clone(), size as usize)?;
+            }
+            if let Some(mode) = mode {
+                self.chmod(kid.clone(), mode as u16)?;
+            }
+            if uid.is_some() || gid.is_some() {
+                self.chown(kid.clone(), uid, gid)?;
+            }
+            if atime.is_some() || mtime.is_some() {
+                self.set_times(kid.clone(), atime.map(to_time), mtime.map(to_time))?;
+            }
+            Ok(kid)
+        });
+        match res {
+            Ok(kid) => reply.attr(&self.config.attr_ttl, &Fs::getattr(self, &kid)),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn mknod(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        let res = self.get_or_enoent(parent).and_then(|parent| {
+            let name = name.to_str().ok_or(FsError::NameInvalid)?;
+            let kid = match mode & libc::S_IFMT {
+                libc::S_IFREG => self.new_file(parent, name, b"")?,
+                libc::S_IFIFO => self.mknod(parent, name, FileType::NamedPipe)?,
+                libc::S_IFSOCK => self.mknod(parent, name, FileType::Socket)?,
+                libc::S_IFCHR => self.mknod_dev(parent, name, FileType::CharDevice, rdev)?,
+                libc::S_IFBLK => self.mknod_dev(parent, name, FileType::BlockDevice, rdev)?,
+                _ => return Err(FsError::InvalidArgument),
+            };
+            self.chmod(kid.clone(), (mode & !umask) as u16)?;
+            Ok(kid)
+        });
+        match res {
+            Ok(kid) => {
+                let attr = Fs::getattr(self, &kid);
+                reply.entry(&self.config.attr_ttl, &attr, self.generation(attr.ino))
+            }
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self
+            .get_or_enoent(ino)
+            .and_then(|kid| Fs::readlink(self, kid))
+        {
+            Ok(target) => reply.data(target.as_bytes()),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        match Fs::open(self, ino) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match Fs::read(self, fh, offset as usize, size as usize) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        match Fs::release(self, fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn flush(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _lock_owner: u64,
+        reply: ReplyEmpty,
+    ) {
+        match self.get_or_enoent(ino).and_then(|kid| Fs::fsync(self, kid)) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn fsync(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.get_or_enoent(ino).and_then(|kid| Fs::fsync(self, kid)) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let entries = match self
+            .get_or_enoent(ino)
+            .and_then(|kid| Fs::readdir(self, kid))
+        {
+            Ok(entries) => entries,
+            Err(e) => return reply.error(e.errno()),
+        };
+
+        for (i, (name, kind, ino)) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(ino, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        match self.get_or_enoent(ino) {
+            Ok(kid) if Fs::access(self, &kid, req.uid(), req.gid(), mask as u16) => reply.ok(),
+            Ok(_) => reply.error(FsError::PermissionDenied.errno()),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        const BLOCK_SIZE: u64 = 512;
+        let st = Fs::statfs(self);
+        let blocks = st.capacity / BLOCK_SIZE;
+        let bfree = st.capacity.saturating_sub(st.bytes) / BLOCK_SIZE;
+        let ffree = self.config.max_nodes.saturating_sub(st.inodes);
+        reply.statfs(
+            blocks,
+            bfree,
+            bfree,
+            st.inodes,
+            ffree,
+            BLOCK_SIZE as u32,
+            self.config.max_name_len as u32,
+            BLOCK_SIZE as u32,
+        );
+    }
+
+    fn setxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        _flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let res = self.get_or_enoent(ino).and_then(|kid| {
+            let name = name.to_str().ok_or(FsError::NameInvalid)?;
+            self.set_xattr(kid, name, value)
+        });
+        match res {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn getxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        let res = self.get_or_enoent(ino).and_then(|kid| {
+            let name = name.to_str().ok_or(FsError::NoXattr)?;
+            self.get_xattr(kid, name)
+        });
+        match res {
+            Ok(value) => reply_xattr(size, &value, reply),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let res = self.get_or_enoent(ino).and_then(|kid| self.list_xattr(kid));
+        match res {
+            Ok(names) => {
+                // names are sent nul terminated and packed together.
+                let mut data = Vec::new();
+                for name in names {
+                    data.extend(name.as_bytes());
+                    data.push(0);
+                }
+                reply_xattr(size, &data, reply)
+            }
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn removexattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let res = self.get_or_enoent(ino).and_then(|kid| {
+            let name = name.to_str().ok_or(FsError::NoXattr)?;
+            self.remove_xattr(kid, name)
+        });
+        match res {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fs::fuse::*;
+    use crate::fs::{kid_ino, FsConfig};
+
+    #[test]
+    fn test_invalidations() {
+        let mut fs = Fs::new();
+        let events = fs.subscribe();
+        let d = fs.new_dir(fs.root(), "d").unwrap();
+        let f = fs.new_file(d.clone(), "f", b"HELLO").unwrap();
+        fs.write_file(f.clone(), 0, b"J").unwrap();
+        fs.rename(d.clone(), "f", fs.root(), "g").unwrap();
+        fs.unlink(fs.root(), "g").unwrap();
+
+        let (d, f) = (kid_ino(&d), kid_ino(&f));
+        let entry = |parent, name: &str| Invalidation::Entry {
+            parent,
+            name: name.to_owned(),
+        };
+        let want = [
+            vec![entry(1, "d")],
+            vec![entry(d, "f")],
+            vec![Invalidation::Inode(f)],
+            vec![entry(d, "f"), entry(1, "g")],
+            vec![entry(1, "g"), Invalidation::Inode(f)],
+        ];
+        let got: Vec<_> = events.try_iter().map(|e| e.invalidations()).collect();
+        assert_eq!(got, want);
+    }
+
+    #[test]
+    fn test_negative_entry() {
+        assert_eq!(Fs::new().negative_entry(), None);
+
+        let config = FsConfig {
+            negative_ttl: Duration::from_secs(5),
+            ..FsConfig::default()
+        };
+        let mut fs = Fs::with_config(config);
+        let (ttl, attr) = fs.negative_entry().unwrap();
+        assert_eq!(ttl, Duration::from_secs(5));
+        assert_eq!(attr.ino, 0);
+        assert_eq!(fs.config.attr_ttl, FsConfig::default().attr_ttl);
+
+        // a cached miss goes once the name is made.
+        let events = fs.subscribe();
+        fs.new_file(fs.root(), "f", b"").unwrap();
+        let want = Invalidation::Entry {
+            parent: 1,
+            name: "f".to_owned(),
+        };
+        assert_eq!(events.try_recv().unwrap().invalidations(), [want]);
+    }
+}
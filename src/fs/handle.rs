@@ -0,0 +1,98 @@
+// open file handles, for callers that read a file many times over.
+
+use super::{Fs, FsError, Kid};
+use std::sync::Arc;
+
+// OpenState is what a handle refers to. it holds on to the node, so an open
+// file can still be read after it is unlinked.
+#[derive(Debug)]
+pub(super) struct OpenState {
+    kid: Kid,
+}
+
+impl Fs {
+    // open gives a new handle on the file with inode number ino.
+    pub fn open(&mut self, ino: u64) -> Result<u64, FsError> {
+        let kid = self.get(ino).ok_or(FsError::NotFound)?;
+        if kid.read().unwrap().to_dir().is_some() {
+            return Err(FsError::IsADirectory);
+        }
+        let fh = self.alloc_handle();
+        self.handles.insert(fh, OpenState { kid });
+        Ok(fh)
+    }
+
+    // read gives up to size bytes from offset in the file open as fh.
+    pub fn read(&self, fh: u64, offset: usize, size: usize) -> Result<Vec<u8>, FsError> {
+        let open = self.handles.get(&fh).ok_or(FsError::BadHandle)?;
+        self.read_at(open.kid.clone(), offset, size)
+    }
+
+    // release closes fh, which may then be given out again.
+    pub fn release(&mut self, fh: u64) -> Result<(), FsError> {
+        self.handles.remove(&fh).ok_or(FsError::BadHandle)?;
+        self.free_handles.push(fh);
+        Ok(())
+    }
+
+    // open_count gives how many handles are open on the node with inode
+    // number ino. handles kept on an unlinked node whose number has since
+    // gone to another don't count.
+    pub fn open_count(&self, ino: u64) -> usize {
+        let Some(live) = self.get(ino) else {
+            return 0;
+        };
+        let open = self.handles.values();
+        open.filter(|open| Arc::ptr_eq(&open.kid, &live)).count()
+    }
+
+    fn alloc_handle(&mut self) -> u64 {
+        if let Some(fh) = self.free_handles.pop() {
+            return fh;
+        }
+        self.handle_alloc += 1;
+        self.handle_alloc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fs::*;
+
+    #[test]
+    fn test_handles() {
+        let mut fs = Fs::new();
+        let d = fs.new_dir(fs.root(), "d").unwrap();
+        let f = fs.new_file(d.clone(), "f", b"HELLO THERE").unwrap();
+        let ino = f.read().unwrap().get_attr().ino;
+
+        let fh = fs.open(ino).unwrap();
+        assert_eq!(fs.open_count(ino), 1);
+        assert_eq!(fs.read(fh, 0, 8).unwrap(), b"HELLO TH");
+        assert_eq!(fs.read(fh, 4, 8).unwrap(), b"O THERE");
+        assert_eq!(fs.read(fh, 20, 8).unwrap(), b"");
+
+        // an unlinked file can be read until it is released.
+        let other = fs.open(ino).unwrap();
+        assert_ne!(other, fh);
+        assert_eq!(fs.open_count(ino), 2);
+        fs.unlink(d.clone(), "f").unwrap();
+        assert_eq!(fs.read(other, 6, 5).unwrap(), b"THERE");
+
+        // a new file given the freed number has none of the old handles.
+        let g = fs.new_file(d.clone(), "g", b"").unwrap();
+        assert_eq!(kid_ino(&g), ino);
+        assert_eq!(fs.open_count(ino), 0);
+        fs.unlink(d.clone(), "g").unwrap();
+
+        fs.release(fh).unwrap();
+        fs.release(other).unwrap();
+        assert_eq!(fs.open_count(ino), 0);
+        assert_eq!(fs.read(fh, 0, 8).err(), Some(FsError::BadHandle));
+        assert_eq!(fs.release(fh).err(), Some(FsError::BadHandle));
+
+        let d_ino = d.read().unwrap().get_attr().ino;
+        assert_eq!(fs.open(d_ino).err(), Some(FsError::IsADirectory));
+        assert_eq!(fs.open(ino).err(), Some(FsError::NotFound));
+    }
+}
@@ -0,0 +1,186 @@
+// copying trees between an Fs and the host filesystem.
+
+use super::{Fs, Kid};
+use std::fs as stdfs;
+use std::io;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::Path;
+
+impl Fs {
+    // import_dir copies everything under the host directory host_path into parent.
+    // special files, and names or link targets that aren't utf-8, are skipped.
+    pub fn import_dir(&mut self, parent: Kid, host_path: &Path) -> io::Result<()> {
+        let mut entries = stdfs::read_dir(host_path)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let path = entry.path();
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => {
+                    log::warn!("import {}: skipping non utf-8 name", path.display());
+                    continue;
+                }
+            };
+            let meta = stdfs::symlink_metadata(&path)?;
+            let kind = meta.file_type();
+            let kid = if kind.is_dir() {
+                let dir = self.new_dir(parent.clone(), &name)?;
+                self.import_dir(dir.clone(), &path)?;
+                dir
+            } else if kind.is_file() {
+                self.new_file(parent.clone(), &name, &stdfs::read(&path)?)?
+            } else if kind.is_symlink() {
+                match stdfs::read_link(&path)?.to_str() {
+                    Some(target) => {
+                        self.symlink(parent.clone(), &name, target)?;
+                    }
+                    None => log::warn!("import {}: skipping non utf-8 target", path.display()),
+                };
+                continue;
+            } else {
+                log::warn!("import {}: skipping special file", path.display());
+                continue;
+            };
+            self.chmod(kid, meta.permissions().mode() as u16)?;
+        }
+        Ok(())
+    }
+
+    // export_dir writes everything under dir out to the host directory host_path,
+    // making it if needed, and applies each node's permission bits.
+    pub fn export_dir(&self, dir: Kid, host_path: &Path) -> io::Result<()> {
+        stdfs::create_dir_all(host_path)?;
+        let kids = self
+            .readdir(dir)?
+            .into_iter()
+            .filter(|(name, _, _)| name != "." && name != "..")
+            .filter_map(|(name, _, ino)| Some((name, self.get(ino)?)))
+            .collect::<Vec<_>>();
+
+        for (name, kid) in kids {
+            let path = host_path.join(&name);
+            let locked = kid.read().unwrap();
+            let perm = locked.get_attr().perm as u32;
+            if let Some(link) = locked.to_symlink() {
+                symlink(&link.target, &path)?;
+                continue;
+            }
+            if locked.to_special().is_some() {
+                log::warn!("export {}: skipping special file", path.display());
+                continue;
+            }
+            if let Some(file) = locked.to_file() {
+                stdfs::write(&path, &file.data)?;
+            }
+            let is_dir = locked.to_dir().is_some();
+            drop(locked);
+
+            // fill a dir in before its perms might stop us writing to it.
+            if is_dir {
+                self.export_dir(kid, &path)?;
+            }
+            stdfs::set_permissions(&path, stdfs::Permissions::from_mode(perm))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fs::host::*;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStringExt;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::PathBuf;
+
+    // temp_dir makes an empty host directory for a test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("demors-{}-{}", name, std::process::id()));
+        let _ = stdfs::remove_dir_all(&dir);
+        stdfs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_import_dir() {
+        let host = temp_dir("import");
+        stdfs::create_dir(host.join("dir1")).unwrap();
+        stdfs::create_dir(host.join("dir2")).unwrap();
+        stdfs::write(host.join("dir1/f1"), b"HELLO").unwrap();
+        stdfs::write(host.join("dir1/f2"), b"HI").unwrap();
+        stdfs::set_permissions(host.join("dir1/f2"), stdfs::Permissions::from_mode(0o600)).unwrap();
+        symlink("dir1/f1", host.join("link")).unwrap();
+        let fifo = CString::new(host.join("fifo").to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+
+        let mut fs = Fs::new();
+        fs.import_dir(fs.root(), &host).unwrap();
+        stdfs::remove_dir_all(&host).unwrap();
+
+        let got: Vec<(String, String)> = fs
+            .iter()
+            .skip(1)
+            .map(|(path, kid)| (path, kid.read().unwrap().to_string()))
+            .collect();
+        let want = [
+            ("/dir1", r#"Dir(["f1", "f2"])"#),
+            ("/dir1/f1", "File(HELLO)"),
+            ("/dir1/f2", "File(HI)"),
+            ("/dir2", "Dir([])"),
+            ("/link", "Symlink(-> dir1/f1)"),
+        ];
+        let want: Vec<(String, String)> = want
+            .iter()
+            .map(|(path, desc)| (path.to_string(), desc.to_string()))
+            .collect();
+        assert_eq!(got, want);
+
+        let f2 = fs.walk(crate::fs::split_path("/dir1/f2")).unwrap();
+        assert_eq!(f2.read().unwrap().get_attr().perm, 0o600);
+    }
+
+    // host_tree describes everything under a host dir as (path, mode, contents).
+    fn host_tree(top: &Path, dir: &Path, out: &mut Vec<(PathBuf, u32, Vec<u8>)>) {
+        let mut entries: Vec<_> = stdfs::read_dir(dir).unwrap().map(|e| e.unwrap()).collect();
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let path = entry.path();
+            let meta = stdfs::symlink_metadata(&path).unwrap();
+            let contents = if meta.is_file() {
+                stdfs::read(&path).unwrap()
+            } else if meta.file_type().is_symlink() {
+                stdfs::read_link(&path).unwrap().into_os_string().into_vec()
+            } else {
+                Vec::new()
+            };
+            let rel = path.strip_prefix(top).unwrap().to_owned();
+            out.push((rel, meta.mode(), contents));
+            if meta.is_dir() {
+                host_tree(top, &path, out);
+            }
+        }
+    }
+
+    #[test]
+    fn test_export_dir() {
+        let host = temp_dir("export-src");
+        stdfs::create_dir_all(host.join("dir1/sub")).unwrap();
+        stdfs::write(host.join("dir1/f1"), b"HELLO").unwrap();
+        stdfs::write(host.join("f2"), b"HI").unwrap();
+        stdfs::set_permissions(host.join("f2"), stdfs::Permissions::from_mode(0o640)).unwrap();
+        symlink("dir1/f1", host.join("link")).unwrap();
+
+        let mut fs = Fs::new();
+        fs.import_dir(fs.root(), &host).unwrap();
+        let out = temp_dir("export-dst").join("out");
+        fs.export_dir(fs.root(), &out).unwrap();
+
+        let (mut want, mut got) = (Vec::new(), Vec::new());
+        host_tree(&host, &host, &mut want);
+        host_tree(&out, &out, &mut got);
+        assert_eq!(want.len(), 5);
+        assert_eq!(got, want);
+        stdfs::remove_dir_all(&host).unwrap();
+        stdfs::remove_dir_all(out.parent().unwrap()).unwrap();
+    }
+}
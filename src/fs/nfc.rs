@@ -0,0 +1,47 @@
+// unicode NFC normalization of names, by way of unicode-normalization.
+use std::borrow::Cow;
+use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
+
+// to_nfc gives name in NFC, borrowing it when it already is.
+pub(super) fn to_nfc(name: &str) -> Cow<'_, str> {
+    if is_nfc_quick(name.chars()) == IsNormalized::Yes {
+        return Cow::Borrowed(name);
+    }
+    let out: String = name.nfc().collect();
+    if out == name {
+        Cow::Borrowed(name)
+    } else {
+        Cow::Owned(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fs::nfc::*;
+
+    #[test]
+    fn test_to_nfc() {
+        assert!(matches!(to_nfc("plain"), Cow::Borrowed("plain")));
+        assert!(matches!(to_nfc("caf\u{e9}"), Cow::Borrowed(_)));
+        assert_eq!(to_nfc("cafe\u{301}"), "caf\u{e9}");
+        assert_eq!(to_nfc("Z\u{30c}lut\u{30c}ouc\u{30c}ky\u{301}"), "Žluťoučký");
+        assert_eq!(to_nfc("A\u{30a}ngstro\u{308}m"), "Ångström");
+
+        // marks with nothing to compose with stay as they are.
+        assert_eq!(to_nfc("\u{301}x\u{301}"), "\u{301}x\u{301}");
+        assert_eq!(to_nfc("e\u{301}\u{301}"), "\u{e9}\u{301}");
+
+        // marks are put in canonical order before composing, so either
+        // order of dot below and dot above gives the same name.
+        let want = "\u{1e69}"; // s with dot below and dot above
+        assert_eq!(to_nfc("s\u{323}\u{307}"), want);
+        assert_eq!(to_nfc("s\u{307}\u{323}"), want);
+        assert_eq!(to_nfc("\u{1e61}\u{323}"), want);
+
+        // outside latin too: hangul jamo and vietnamese stacked marks.
+        assert_eq!(to_nfc("\u{1112}\u{1161}\u{11ab}"), "\u{d55c}");
+        assert_eq!(to_nfc("Vie\u{323}\u{302}t"), "Vi\u{1ec7}t");
+        // and singletons such as the ohm sign become what they stand for.
+        assert_eq!(to_nfc("\u{2126}"), "\u{3a9}");
+    }
+}
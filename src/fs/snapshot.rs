@@ -0,0 +1,556 @@
+// snapshots of the tree on disk.
+//
+// a snapshot is a header followed by one record per directory entry, parents
+// before their kids and kids in name order. all numbers are big endian.
+//
+//   header: MAGIC, u32 VERSION, u64 record count
+//   record: u64 parent ino (0 for the root), name, u64 ino, u8 tag, then
+//     LINK: nothing more, the ino was already recorded under another name
+//     DIR/FILE/SYMLINK/SPECIAL: attrs, then file data (u64 length), symlink
+//       target, or a u8 kind for a special node (KIND_* below)
+//   attrs: u16 perm, u32 uid, u32 gid, atime, mtime, ctime, crtime,
+//     u32 rdev, u32 flags, u32 xattr count and each xattr's name and value
+//   times are a u64 of seconds and a u32 of nanoseconds since the epoch.
+//   names, targets and xattr values are a u32 length and that many bytes.
+
+use super::{validate_name, Dir, DispElem, File, Fs, FsConfig, FsError, Kid, Special, Symlink};
+use fuser::{FileAttr, FileType};
+use std::collections::{HashMap, HashSet};
+use std::fs as stdfs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAGIC: &[u8; 8] = b"DEMORSFS";
+const VERSION: u32 = 1;
+
+const TAG_DIR: u8 = b'D';
+const TAG_FILE: u8 = b'F';
+const TAG_SYMLINK: u8 = b'S';
+const TAG_LINK: u8 = b'L';
+const TAG_SPECIAL: u8 = b'P';
+
+const KIND_FIFO: u8 = b'p';
+const KIND_SOCKET: u8 = b's';
+const KIND_CHAR: u8 = b'c';
+const KIND_BLOCK: u8 = b'b';
+
+impl Fs {
+    // save writes a snapshot of the whole tree to path.
+    pub fn save(&self, path: &Path) -> Result<(), FsError> {
+        stdfs::write(path, self.snapshot()).map_err(|e| FsError::Io(e.kind()))
+    }
+
+    // snapshot encodes the whole tree.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut w = Vec::new();
+        let mut seen = HashSet::new();
+        let mut count = 0;
+        put_record(&mut w, &mut seen, &mut count, 0, "", self.root());
+
+        let mut buf = Vec::with_capacity(w.len() + 20);
+        buf.extend(MAGIC);
+        buf.extend(VERSION.to_be_bytes());
+        buf.extend((count as u64).to_be_bytes());
+        buf.extend(w);
+        buf
+    }
+
+    // load reads a tree saved by save.
+    pub fn load(path: &Path) -> Result<Fs, FsError> {
+        let buf = stdfs::read(path).map_err(|e| FsError::Io(e.kind()))?;
+        Self::from_snapshot(&buf)
+    }
+
+    // from_snapshot rebuilds a tree from a snapshot, keeping its inode numbers.
+    pub fn from_snapshot(buf: &[u8]) -> Result<Fs, FsError> {
+        Self::from_snapshot_with(FsConfig::default(), buf)
+    }
+
+    // from_snapshot_with is from_snapshot for an fs made with config. a tree
+    // holding more nodes or file data than config allows, or nested deeper,
+    // fails with the error creating it would have. names are normalized and
+    // checked as new_file would, and one it would refuse is a BadSnapshot.
+    pub fn from_snapshot_with(config: FsConfig, buf: &[u8]) -> Result<Fs, FsError> {
+        let mut r = Reader { buf };
+        if r.take(MAGIC.len())? != MAGIC {
+            return Err(FsError::BadSnapshot);
+        }
+        let version = r.u32()?;
+        if version != VERSION {
+            return Err(FsError::SnapshotVersion(version));
+        }
+        let count = r.u64()?;
+
+        let mut fs = Fs::with_config(config);
+        let mut by_ino: HashMap<u64, Kid> = HashMap::new();
+        for i in 0..count {
+            let parent_ino = r.u64()?;
+            let name = r.name()?;
+            let ino = r.u64()?;
+            let tag = r.u8()?;
+            if i == 0 {
+                // the root, which Fs::with_config already made.
+                if parent_ino != 0 || !name.is_empty() || ino != 1 || tag != TAG_DIR {
+                    return Err(FsError::BadSnapshot);
+                }
+                let root = fs.root();
+                read_node(&mut r, &mut **root.write().unwrap(), tag)?;
+                by_ino.insert(ino, root);
+                continue;
+            }
+
+            let name = fs.norm_name(&name).into_owned();
+            validate_name(&name, fs.config.max_name_len).map_err(|_| FsError::BadSnapshot)?;
+            let parent = by_ino.get(&parent_ino).ok_or(FsError::BadSnapshot)?.clone();
+            let kid = if tag == TAG_LINK {
+                let kid = by_ino.get(&ino).ok_or(FsError::BadSnapshot)?.clone();
+                let mut locked = kid.write().unwrap();
+                if locked.to_dir().is_some() {
+                    return Err(FsError::BadSnapshot);
+                }
+                locked.get_mut_attr().nlink += 1;
+                drop(locked);
+                kid
+            } else {
+                if ino == 0 || by_ino.contains_key(&ino) {
+                    return Err(FsError::BadSnapshot);
+                }
+                fs.check_limits(&parent)?;
+                let kid = new_node(&mut r, &fs.config, ino, tag)?;
+                let mut locked = kid.write().unwrap();
+                locked.set_parent(Arc::downgrade(&parent));
+                let size = locked.to_file().map_or(0, |file| file.attr.size);
+                fs.check_space(0, size)?;
+                fs.used_bytes += size;
+                drop(locked);
+                by_ino.insert(ino, kid.clone());
+                fs.inodes.insert(ino, kid.clone());
+                fs.inode_alloc = fs.inode_alloc.max(ino);
+                kid
+            };
+
+            let is_dir = kid.read().unwrap().to_dir().is_some();
+            let mut locked = parent.write().unwrap();
+            let dir = locked.to_mut_dir().ok_or(FsError::BadSnapshot)?;
+            fs.check_collision(dir, &name)
+                .map_err(|_| FsError::BadSnapshot)?;
+            dir.kids.insert(name, kid.clone());
+            if is_dir {
+                dir.attr.nlink += 1; // for the kid's ".."
+            }
+        }
+        if !r.buf.is_empty() {
+            return Err(FsError::BadSnapshot);
+        }
+        Ok(fs)
+    }
+}
+
+// new_node makes the node described by the rest of a record.
+fn new_node(r: &mut Reader, config: &FsConfig, ino: u64, tag: u8) -> Result<Kid, FsError> {
+    let kid = match tag {
+        TAG_DIR => Dir::new(config, ino).into_kid(),
+        TAG_FILE => File::new(config, ino, b"").into_kid(),
+        TAG_SYMLINK => Symlink::new(config, ino, "").into_kid(),
+        TAG_SPECIAL => Special::new(config, ino, FileType::NamedPipe, 0).into_kid(),
+        _ => return Err(FsError::BadSnapshot),
+    };
+    read_node(r, &mut **kid.write().unwrap(), tag)?;
+    Ok(kid)
+}
+
+// read_node fills in a node's attrs, xattrs and contents from the rest of a record.
+fn read_node(r: &mut Reader, node: &mut dyn DispElem, tag: u8) -> Result<(), FsError> {
+    let attr = node.get_mut_attr();
+    attr.perm = r.u16()?;
+    attr.uid = r.u32()?;
+    attr.gid = r.u32()?;
+    attr.atime = r.time()?;
+    attr.mtime = r.time()?;
+    attr.ctime = r.time()?;
+    attr.crtime = r.time()?;
+    attr.rdev = r.u32()?;
+    attr.flags = r.u32()?;
+
+    let nxattrs = r.u32()?;
+    for _ in 0..nxattrs {
+        let name = r.name()?;
+        let value = r.bytes()?.to_vec();
+        if let Some(xattrs) = node.get_mut_xattrs() {
+            xattrs.insert(name, value);
+        }
+    }
+
+    match tag {
+        TAG_FILE => {
+            let n = r.u64()?;
+            let n = usize::try_from(n).map_err(|_| FsError::BadSnapshot)?;
+            let data = r.take(n)?.to_vec();
+            let file = node.to_mut_file().ok_or(FsError::BadSnapshot)?;
+            file.data = data;
+            file.update_size();
+        }
+        TAG_SYMLINK => {
+            let target = r.name()?;
+            let link = node.to_mut_symlink().ok_or(FsError::BadSnapshot)?;
+            link.attr.size = target.len() as u64;
+            link.target = target;
+        }
+        TAG_SPECIAL => {
+            node.get_mut_attr().kind = match r.u8()? {
+                KIND_FIFO => FileType::NamedPipe,
+                KIND_SOCKET => FileType::Socket,
+                KIND_CHAR => FileType::CharDevice,
+                KIND_BLOCK => FileType::BlockDevice,
+                _ => return Err(FsError::BadSnapshot),
+            };
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// Reader takes fields off the front of a snapshot.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], FsError> {
+        if self.buf.len() < n {
+            return Err(FsError::BadSnapshot);
+        }
+        let (hd, tl) = self.buf.split_at(n);
+        self.buf = tl;
+        Ok(hd)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], FsError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, FsError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, FsError> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, FsError> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, FsError> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+
+    fn time(&mut self) -> Result<SystemTime, FsError> {
+        let secs = self.u64()?;
+        let nanos = self.u32()?;
+        if nanos >= 1_000_000_000 {
+            return Err(FsError::BadSnapshot);
+        }
+        UNIX_EPOCH
+            .checked_add(Duration::new(secs, nanos))
+            .ok_or(FsError::BadSnapshot)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], FsError> {
+        let n = self.u32()? as usize;
+        self.take(n)
+    }
+
+    fn name(&mut self) -> Result<String, FsError> {
+        let bs = self.bytes()?;
+        String::from_utf8(bs.to_vec()).map_err(|_| FsError::BadSnapshot)
+    }
+}
+
+fn put_record(
+    w: &mut Vec<u8>,
+    seen: &mut HashSet<u64>,
+    count: &mut usize,
+    parent_ino: u64,
+    name: &str,
+    k: Kid,
+) {
+    // lock once, and don't hold the lock while visiting kids.
+    let locked = k.read().unwrap();
+    let attr = locked.get_attr();
+    *count += 1;
+    w.extend(parent_ino.to_be_bytes());
+    put_bytes(w, name.as_bytes());
+    w.extend(attr.ino.to_be_bytes());
+    if !seen.insert(attr.ino) {
+        w.push(TAG_LINK);
+        return;
+    }
+
+    let tag = if locked.to_dir().is_some() {
+        TAG_DIR
+    } else if locked.to_symlink().is_some() {
+        TAG_SYMLINK
+    } else if locked.to_special().is_some() {
+        TAG_SPECIAL
+    } else {
+        TAG_FILE
+    };
+    w.push(tag);
+    put_attr(w, attr);
+    let mut xattrs: Vec<_> = locked.get_xattrs().into_iter().flatten().collect();
+    xattrs.sort();
+    w.extend((xattrs.len() as u32).to_be_bytes());
+    for (name, value) in xattrs {
+        put_bytes(w, name.as_bytes());
+        put_bytes(w, value);
+    }
+
+    if let Some(file) = locked.to_file() {
+        w.extend((file.data.len() as u64).to_be_bytes());
+        w.extend(&file.data);
+    }
+    if let Some(link) = locked.to_symlink() {
+        put_bytes(w, link.target.as_bytes());
+    }
+    if locked.to_special().is_some() {
+        w.push(match attr.kind {
+            FileType::NamedPipe => KIND_FIFO,
+            FileType::CharDevice => KIND_CHAR,
+            FileType::BlockDevice => KIND_BLOCK,
+            _ => KIND_SOCKET,
+        });
+    }
+    let kids = match locked.to_dir() {
+        Some(dir) => dir
+            .kids
+            .iter()
+            .map(|(nm, kid)| (nm.clone(), kid.clone()))
+            .collect(),
+        None => Vec::new(),
+    };
+    let ino = attr.ino;
+    drop(locked);
+
+    for (nm, kid) in kids {
+        put_record(w, seen, count, ino, &nm, kid);
+    }
+}
+
+fn put_attr(w: &mut Vec<u8>, attr: &FileAttr) {
+    w.extend(attr.perm.to_be_bytes());
+    w.extend(attr.uid.to_be_bytes());
+    w.extend(attr.gid.to_be_bytes());
+    for t in [attr.atime, attr.mtime, attr.ctime, attr.crtime] {
+        put_time(w, t);
+    }
+    w.extend(attr.rdev.to_be_bytes());
+    w.extend(attr.flags.to_be_bytes());
+}
+
+// put_time writes t as time since the epoch. earlier times are saved as the epoch.
+fn put_time(w: &mut Vec<u8>, t: SystemTime) {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    w.extend(d.as_secs().to_be_bytes());
+    w.extend(d.subsec_nanos().to_be_bytes());
+}
+
+fn put_bytes(w: &mut Vec<u8>, bs: &[u8]) {
+    w.extend((bs.len() as u32).to_be_bytes());
+    w.extend(bs);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fs::snapshot::*;
+    use crate::fs::{split_path, FsStat};
+
+    #[test]
+    fn test_snapshot() {
+        let mut fs = Fs::new();
+        let d1 = fs.new_dir(fs.root(), "dir1").unwrap();
+        fs.new_dir(fs.root(), "dir2").unwrap();
+        let f1 = fs.new_file(d1.clone(), "f1", b"HELLO").unwrap();
+        fs.new_file(d1.clone(), "f2", b"HI").unwrap();
+        fs.link(f1, fs.root(), "hard").unwrap();
+
+        let snap = fs.snapshot();
+        assert_eq!(&snap[..8], MAGIC);
+        assert_eq!(snap[8..12], VERSION.to_be_bytes());
+        assert_eq!(snap[12..20], 6u64.to_be_bytes());
+        assert_eq!(snap, fs.snapshot());
+
+        // the hard link is recorded by inode, so the data is only saved once.
+        let hellos = snap.windows(5).filter(|w| w == b"HELLO").count();
+        assert_eq!(hellos, 1);
+        assert_eq!(&snap[snap.len() - 13..snap.len() - 9], b"hard");
+        assert_eq!(snap[snap.len() - 1], TAG_LINK);
+
+        let path = std::env::temp_dir().join(format!("demors-save-{}", std::process::id()));
+        fs.save(&path).unwrap();
+        assert_eq!(stdfs::read(&path).unwrap(), snap);
+        stdfs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load() {
+        let mut fs = Fs::new();
+        let d1 = fs.new_dir(fs.root(), "dir1").unwrap();
+        fs.new_dir(d1.clone(), "sub").unwrap();
+        let f1 = fs.new_file(d1.clone(), "f1", b"HELLO").unwrap();
+        fs.new_file(fs.root(), "f2", b"HI").unwrap();
+        fs.link(f1.clone(), fs.root(), "hard").unwrap();
+        fs.symlink(fs.root(), "link", "dir1/f1").unwrap();
+        fs.chmod(f1.clone(), 0o600).unwrap();
+        fs.mknod(fs.root(), "fifo", FileType::NamedPipe).unwrap();
+        fs.mknod(d1.clone(), "sock", FileType::Socket).unwrap();
+        fs.mknod_dev(fs.root(), "tty", FileType::CharDevice, 0x400)
+            .unwrap();
+        fs.set_xattr(d1, "user.comment", b"hi").unwrap();
+
+        let path = std::env::temp_dir().join(format!("demors-load-{}", std::process::id()));
+        fs.save(&path).unwrap();
+        let mut loaded = Fs::load(&path).unwrap();
+        stdfs::remove_file(&path).unwrap();
+
+        // saving it again gives back exactly the same snapshot.
+        assert_eq!(loaded.snapshot(), fs.snapshot());
+        assert_eq!(loaded.statfs(), fs.statfs());
+        let walk = |fs: &Fs, path| fs.walk(split_path(path)).unwrap();
+        let attr = |k: &Kid| *k.read().unwrap().get_attr();
+        for path in [
+            "/",
+            "/dir1",
+            "/dir1/sub",
+            "/dir1/f1",
+            "/link",
+            "/fifo",
+            "/dir1/sock",
+            "/tty",
+        ] {
+            assert_eq!(attr(&walk(&loaded, path)), attr(&walk(&fs, path)));
+        }
+
+        // both names still share one node.
+        let (f1, hard) = (walk(&loaded, "/dir1/f1"), walk(&loaded, "/hard"));
+        assert!(Arc::ptr_eq(&f1, &hard));
+        assert_eq!(attr(&f1).nlink, 2);
+        loaded.write_file(hard, 0, b"J").unwrap();
+        assert_eq!(f1.read().unwrap().to_file().unwrap().data, b"JELLO");
+
+        // new inodes don't collide with loaded ones.
+        let f3 = loaded.new_file(loaded.root(), "f3", b"").unwrap();
+        assert_eq!(attr(&f3).ino, fs.inode_alloc + 1);
+    }
+
+    #[test]
+    fn test_load_config() {
+        let mut fs = Fs::new();
+        let d = fs.new_dir(fs.root(), "d").unwrap();
+        fs.new_file(d.clone(), "f1", b"HELLO").unwrap();
+        fs.new_file(d, "f2", b"HI").unwrap();
+        let snap = fs.snapshot();
+
+        // the config is kept rather than replaced by the default.
+        let config = FsConfig {
+            read_only: true,
+            capacity_bytes: 7,
+            max_nodes: 4,
+            max_depth: 2,
+            ..FsConfig::default()
+        };
+        let loaded = Fs::from_snapshot_with(config, &snap).unwrap();
+        assert!(loaded.read_only());
+        assert_eq!(
+            loaded.statfs(),
+            FsStat {
+                capacity: 7,
+                ..fs.statfs()
+            }
+        );
+        assert_eq!(loaded.snapshot(), snap);
+
+        // but a tree over any of its limits isn't loaded.
+        let over = [
+            (
+                FsConfig {
+                    capacity_bytes: 6,
+                    ..config
+                },
+                FsError::NoSpace,
+            ),
+            (
+                FsConfig {
+                    max_nodes: 3,
+                    ..config
+                },
+                FsError::NoSpace,
+            ),
+            (
+                FsConfig {
+                    max_depth: 1,
+                    ..config
+                },
+                FsError::TooDeep,
+            ),
+        ];
+        for (config, err) in over {
+            assert_eq!(Fs::from_snapshot_with(config, &snap).err(), Some(err));
+        }
+
+        // names go through the config's rules too.
+        let mut fs = Fs::new();
+        fs.new_file(fs.root(), "cafe\u{301}", b"").unwrap();
+        fs.new_file(fs.root(), "F1", b"").unwrap();
+        fs.new_file(fs.root(), "f1", b"").unwrap();
+        let snap = fs.snapshot();
+        let folded = FsConfig {
+            case_insensitive: true,
+            ..FsConfig::default()
+        };
+        let err = Fs::from_snapshot_with(folded, &snap).err();
+        assert_eq!(err, Some(FsError::BadSnapshot));
+        let short = FsConfig {
+            max_name_len: 4,
+            ..FsConfig::default()
+        };
+        let err = Fs::from_snapshot_with(short, &snap).err();
+        assert_eq!(err, Some(FsError::BadSnapshot));
+
+        fs.unlink(fs.root(), "F1").unwrap();
+        let nfc = FsConfig {
+            normalize_nfc: true,
+            ..FsConfig::default()
+        };
+        let loaded = Fs::from_snapshot_with(nfc, &fs.snapshot()).unwrap();
+        let names: Vec<_> = loaded.iter().map(|(path, _)| path).collect();
+        assert_eq!(names, ["/", "/caf\u{e9}", "/f1"]);
+    }
+
+    #[test]
+    fn test_load_bad() {
+        let mut fs = Fs::new();
+        fs.new_file(fs.root(), "f1", b"HELLO").unwrap();
+        let snap = fs.snapshot();
+
+        for n in 0..snap.len() {
+            let err = Fs::from_snapshot(&snap[..n]).err();
+            assert_eq!(err, Some(FsError::BadSnapshot));
+        }
+        let mut bad = snap.clone();
+        bad[0] = b'X';
+        assert_eq!(Fs::from_snapshot(&bad).err(), Some(FsError::BadSnapshot));
+        let mut bad = snap.clone();
+        bad[8..12].copy_from_slice(&2u32.to_be_bytes());
+        let err = Fs::from_snapshot(&bad).err();
+        assert_eq!(err, Some(FsError::SnapshotVersion(2)));
+        let mut bad = snap.clone();
+        bad.push(0);
+        assert_eq!(Fs::from_snapshot(&bad).err(), Some(FsError::BadSnapshot));
+
+        let missing = std::env::temp_dir().join("demors-no-such-snapshot");
+        let err = Fs::load(&missing).err();
+        assert_eq!(err, Some(FsError::Io(std::io::ErrorKind::NotFound)));
+    }
+}
@@ -0,0 +1,4 @@
+pub mod dispatch;
+pub mod fs;
+pub mod server;
+pub mod trans;
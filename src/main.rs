@@ -1,20 +1,45 @@
-mod fs;
-mod trans;
+use demors::fs;
+use demors::trans::Trans;
+use fuser::MountOption;
+use std::env;
+use std::path::Path;
 
-use trans::Trans;
+fn demo_fs() -> fs::Fs {
+    let mut fs = fs::Fs::new();
+    let d1 = fs.new_dir(fs.root(), "dir1").unwrap();
+    fs.new_dir(fs.root(), "dir2").unwrap();
+    fs.new_file(d1.clone(), "f1", b"HELLO").unwrap();
+    fs.new_file(d1.clone(), "f2", b"HI").unwrap();
+    fs
+}
 
 fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() == 3 && args[1] == "mount" {
+        let mut fs = demo_fs();
+        fs.set_read_only(true);
+        let options = [MountOption::FSName("demors".to_owned())];
+        if let Err(e) = fs.mount(Path::new(&args[2]), &options) {
+            eprintln!("mount {}: {}", args[2], e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let mut trans = Trans::new();
-    trans.add_arg("hello".as_bytes().to_vec());
+    trans.add_arg("hello".as_bytes().to_vec()).unwrap();
     println!("trans {:?} arg mode={}", trans, trans.arg_mode());
     let args = trans.take_args(2);
     println!("args {:?}", args);
 
-    trans.add_arg("world".as_bytes().to_vec());
-    trans.set_resp("HELLO".as_bytes().to_vec());
+    trans.add_arg("world".as_bytes().to_vec()).unwrap();
+    let args = trans.take_args(2);
+    println!("args {:?}", args);
+
+    trans.set_resp("HELLO".as_bytes().to_vec()).unwrap();
     for _ in 0..3 {
         let bs = &trans.read_resp(3);
-        let d = String::from_utf8_lossy(&bs);
+        let d = String::from_utf8_lossy(bs);
         println!(
             "trans {:?} arg mode={}, data={}",
             trans,
@@ -22,8 +47,6 @@ fn main() {
             d
         );
     }
-    let args = trans.take_args(2);
-    println!("args {:?}", args);
 
     //let mut fs = fs::Fs::new();
     if true {
@@ -32,9 +55,9 @@ fn main() {
 
         {
             let d1 = fs.new_dir(fs.root(), "dir1").unwrap();
-            fs.new_dir(fs.root(), "dir2");
-            fs.new_file(d1.clone(), "f1", "HELLO");
-            fs.new_file(d1.clone(), "f2", "HI");
+            fs.new_dir(fs.root(), "dir2").unwrap();
+            fs.new_file(d1.clone(), "f1", b"HELLO").unwrap();
+            fs.new_file(d1.clone(), "f2", b"HI").unwrap();
             fs.show_tree();
         }
 
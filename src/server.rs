@@ -0,0 +1,290 @@
+use crate::dispatch;
+use crate::fs::Fs;
+use crate::trans::Trans;
+use std::fs as stdfs;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+// ShutdownHandle controls a server started by one of the serve functions,
+// whose accept loop runs on its own thread.
+pub struct ShutdownHandle {
+    stop: Arc<AtomicBool>,
+    wake: Box<dyn Fn() -> io::Result<()> + Send>, // unblocks a pending accept
+    thread: JoinHandle<io::Result<()>>,
+}
+
+impl ShutdownHandle {
+    // shutdown stops accepting connections, lets each open connection finish
+    // the transaction it is on, and waits for all of them to close.
+    pub fn shutdown(self) -> io::Result<()> {
+        self.stop.store(true, Ordering::SeqCst);
+        // if the accept loop already stopped on an error there's nothing to wake.
+        let _ = (self.wake)();
+        self.wait()
+    }
+
+    // wait blocks until the server stops, giving the error that stopped it.
+    pub fn wait(self) -> io::Result<()> {
+        self.thread.join().expect("accept loop panicked")
+    }
+}
+
+// serve_tcp listens on addr and serves the protocol against fs until shut down.
+pub fn serve_tcp(fs: Arc<Mutex<Fs>>, addr: &str) -> io::Result<ShutdownHandle> {
+    serve_tcp_listener(fs, TcpListener::bind(addr)?)
+}
+
+// serve_tcp_listener serves connections accepted from listener, each on its own thread.
+pub fn serve_tcp_listener(fs: Arc<Mutex<Fs>>, listener: TcpListener) -> io::Result<ShutdownHandle> {
+    let addr = listener.local_addr()?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let flag = stop.clone();
+    Ok(ShutdownHandle {
+        stop,
+        wake: Box::new(move || TcpStream::connect(addr).map(drop)),
+        thread: thread::spawn(move || accept_loop(fs, listener.incoming(), &flag)),
+    })
+}
+
+// serve_unix listens on a unix socket at path and serves the protocol against fs
+// until shut down.
+pub fn serve_unix(fs: Arc<Mutex<Fs>>, path: &Path) -> io::Result<ShutdownHandle> {
+    serve_unix_listener(fs, bind_unix(path)?)
+}
+
+// bind_unix listens on a unix socket at path, first removing any stale
+// socket left there by an earlier server. other kinds of file are left alone.
+pub fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    match stdfs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => stdfs::remove_file(path)?,
+        _ => {}
+    }
+    UnixListener::bind(path)
+}
+
+// serve_unix_listener serves connections accepted from listener, each on its own thread.
+pub fn serve_unix_listener(
+    fs: Arc<Mutex<Fs>>,
+    listener: UnixListener,
+) -> io::Result<ShutdownHandle> {
+    let path = listener
+        .local_addr()?
+        .as_pathname()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "unnamed unix socket"))?
+        .to_owned();
+    let stop = Arc::new(AtomicBool::new(false));
+    let flag = stop.clone();
+    Ok(ShutdownHandle {
+        stop,
+        wake: Box::new(move || UnixStream::connect(&path).map(drop)),
+        thread: thread::spawn(move || accept_loop(fs, listener.incoming(), &flag)),
+    })
+}
+
+// Conn is a stream the servers accept connections as.
+trait Conn: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn shutdown_read(&self) -> io::Result<()>;
+}
+
+impl Conn for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown_read(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Read)
+    }
+}
+
+impl Conn for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown_read(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Read)
+    }
+}
+
+// accept_loop spawns a thread for each connection until stop is set or the
+// listener fails, then drains the connections still open. closing their read
+// side makes each one stop after answering the transaction it is on. a
+// connection lost while being accepted is logged and skipped.
+fn accept_loop<S: Conn>(
+    fs: Arc<Mutex<Fs>>,
+    incoming: impl Iterator<Item = io::Result<S>>,
+    stop: &AtomicBool,
+) -> io::Result<()> {
+    let mut conns: Vec<(S, JoinHandle<()>)> = Vec::new();
+    let mut res = Ok(());
+    for stream in incoming {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+        // keep a handle on each connection to close it with later.
+        let (ctl, r, w) = match stream.and_then(|s| Ok((s.try_clone()?, s.try_clone()?, s))) {
+            Ok(conn) => conn,
+            Err(e) if listener_failed(&e) => {
+                res = Err(e);
+                break;
+            }
+            Err(e) => {
+                log::warn!("accept: {}", e);
+                continue;
+            }
+        };
+        conns.retain(|(_, conn)| !conn.is_finished());
+        conns.push((ctl, spawn_conn(fs.clone(), r, w)));
+    }
+    for (ctl, conn) in conns {
+        // the peer may have hung up already, which is just as good.
+        let _ = ctl.shutdown_read();
+        let _ = conn.join();
+    }
+    res
+}
+
+// listener_failed tells an error from the listener itself, after which no
+// accept can succeed, from one that only cost the connection being accepted.
+fn listener_failed(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EBADF | libc::EINVAL | libc::ENOTSOCK | libc::EOPNOTSUPP | libc::EFAULT)
+    )
+}
+
+fn spawn_conn<R, W>(fs: Arc<Mutex<Fs>>, r: R, w: W) -> JoinHandle<()>
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    thread::spawn(move || {
+        if let Err(e) = serve_conn(&fs, r, w) {
+            log::warn!("connection: {}", e);
+        }
+    })
+}
+
+// serve_conn answers each transaction read from r on w until r is closed.
+fn serve_conn(fs: &Mutex<Fs>, r: impl Read, w: impl Write) -> io::Result<()> {
+    let mut r = BufReader::new(r);
+    let mut w = BufWriter::new(w);
+    let mut session = dispatch::Session::new(&fs.lock().unwrap());
+    loop {
+        let mut trans = match Trans::read_from(&mut r) {
+            Ok(trans) => trans,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        session.handle(&mut fs.lock().unwrap(), &mut trans);
+        trans.write_to(&mut w)?;
+        w.flush()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fs::split_path;
+    use crate::server::*;
+    use crate::trans::TransBuilder;
+
+    // call sends a command over stream and gives back the response.
+    fn call(stream: &mut (impl Read + Write), args: &[&str]) -> String {
+        let trans = TransBuilder::new().args(args.iter().copied()).build();
+        trans.write_to(stream).unwrap();
+        let mut reply = Trans::read_from(stream).unwrap();
+        let n = reply.resp_remaining();
+        String::from_utf8(reply.read_resp(n)).unwrap()
+    }
+
+    #[test]
+    fn test_serve_tcp() {
+        let fs = Arc::new(Mutex::new(Fs::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _server = serve_tcp_listener(fs, listener).unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        assert_eq!(call(&mut stream, &["mkdir", "/dir1"]), "");
+        assert_eq!(call(&mut stream, &["mkdir", "/dir2"]), "");
+        assert_eq!(call(&mut stream, &["ls", "/"]), "dir1/\ndir2/\n");
+
+        // other connections share the same tree.
+        let mut other = TcpStream::connect(addr).unwrap();
+        assert_eq!(call(&mut other, &["ls", "/"]), "dir1/\ndir2/\n");
+    }
+
+    #[test]
+    fn test_serve_unix() {
+        let dir = std::env::temp_dir().join(format!("demors-test-{}", std::process::id()));
+        stdfs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sock");
+
+        // leave a stale socket behind, as a crashed server would.
+        drop(UnixListener::bind(&path).unwrap());
+        let listener = bind_unix(&path).unwrap();
+        let fs = Arc::new(Mutex::new(Fs::new()));
+        let _server = serve_unix_listener(fs, listener).unwrap();
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        assert_eq!(call(&mut stream, &["mkdir", "/dir1"]), "");
+        assert_eq!(call(&mut stream, &["ls", "/"]), "dir1/\n");
+        assert_eq!(
+            call(&mut stream, &["cat", "/dir1"]),
+            "error: is a directory\n"
+        );
+
+        // a file that isn't a socket is never removed.
+        let file = dir.join("file");
+        stdfs::write(&file, b"keep").unwrap();
+        assert!(bind_unix(&file).is_err());
+        assert_eq!(stdfs::read(&file).unwrap(), b"keep");
+        stdfs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let fs = Arc::new(Mutex::new(Fs::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = serve_tcp_listener(fs.clone(), listener).unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        assert_eq!(call(&mut stream, &["mkdir", "/dir1"]), "");
+
+        // the open connection is drained rather than left behind.
+        server.shutdown().unwrap();
+        let mut buf = [0; 1];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        assert!(TcpStream::connect(addr).is_err());
+        assert!(fs.lock().unwrap().walk(split_path("/dir1")).is_ok());
+    }
+
+    #[test]
+    fn test_accept_errors() {
+        let fs = Arc::new(Mutex::new(Fs::new()));
+        let (conn, mut peer) = UnixStream::pair().unwrap();
+        let incoming = vec![
+            Err(io::Error::from(io::ErrorKind::ConnectionAborted)),
+            Err(io::Error::from_raw_os_error(libc::EMFILE)),
+            Ok(conn),
+            Err(io::Error::from_raw_os_error(libc::EBADF)),
+            Err(io::Error::from(io::ErrorKind::ConnectionAborted)),
+        ];
+        let stop = AtomicBool::new(false);
+
+        // a lost connection doesn't stop the loop; a failed listener does,
+        // once the connections it accepted are closed.
+        let err = accept_loop(fs, incoming.into_iter(), &stop).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+        let mut buf = [0; 1];
+        assert_eq!(peer.read(&mut buf).unwrap(), 0);
+    }
+}
@@ -1,52 +1,865 @@
+use std::borrow::Cow;
 use std::cmp;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::str::{self, Utf8Error};
+
+// default for the longest arg or response read_from will accept.
+pub const MAX_FRAME_LEN: usize = 16 << 20;
+
+// default for how many bytes of args and response together read_from will accept.
+pub const MAX_TRANS_LEN: usize = 64 << 20;
+
+// defaults for how many args may be queued and how big a response may get.
+pub const DEFAULT_MAX_ARGS: usize = 1 << 16;
+pub const DEFAULT_MAX_RESP_BYTES: usize = 1 << 30;
+
+// the status of a successful transaction. any other status is an error code
+// chosen by whoever set the response.
+pub const STATUS_OK: u8 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransError {
+    NotInArgMode,  // a response is pending
+    ArgsPending,   // args are still queued
+    Truncated,     // encoded input ended early
+    TrailingData,  // encoded input has bytes past the end
+    LimitExceeded, // too many args or too big a response
+}
+
+impl fmt::Display for TransError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            TransError::NotInArgMode => "not in arg mode",
+            TransError::ArgsPending => "args still pending",
+            TransError::Truncated => "truncated transaction",
+            TransError::TrailingData => "trailing data after transaction",
+            TransError::LimitExceeded => "transaction limit exceeded",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+impl std::error::Error for TransError {}
+
+// Phase is which half of a transaction is under way. it moves from Args to
+// Resp once a response is set, and only reset moves it back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Args,
+    Resp,
+}
 
 // Trans is a trasaction.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Trans {
     args: Vec<Vec<u8>>,
-    resp: Vec<u8>,
+    resp: VecDeque<u8>, // kept contiguous so it can be peeked
+    max_args: usize,
+    max_resp_bytes: usize,
+    status: u8,
+    phase: Phase,
+}
+
+impl Default for Trans {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Trans {
     pub fn new() -> Self {
+        Self::with_limits(DEFAULT_MAX_ARGS, DEFAULT_MAX_RESP_BYTES)
+    }
+
+    // with_limits makes a transaction that holds at most max_args queued args
+    // and max_resp_bytes of unread response.
+    pub fn with_limits(max_args: usize, max_resp_bytes: usize) -> Self {
         Trans {
             args: Vec::new(),
-            resp: Vec::new(),
+            resp: VecDeque::new(),
+            max_args,
+            max_resp_bytes,
+            status: STATUS_OK,
+            phase: Phase::Args,
         }
     }
 
+    // reset empties the transaction for reuse, keeping its allocations.
+    pub fn reset(&mut self) {
+        self.args.clear();
+        self.resp.clear();
+        self.status = STATUS_OK;
+        self.phase = Phase::Args;
+    }
+
+    // arg_mode says args may still be added: no response has been set since
+    // the transaction was made or reset, even one since read to the end.
     pub fn arg_mode(&self) -> bool {
-        return self.resp.len() == 0;
+        self.phase == Phase::Args
+    }
+
+    // num_args is how many args are queued.
+    pub fn num_args(&self) -> usize {
+        self.args.len()
     }
 
-    pub fn add_arg(&mut self, dat: Vec<u8>) {
-        // TODO error if not in arg mode
+    // status says how the transaction went, STATUS_OK unless set otherwise.
+    pub fn status(&self) -> u8 {
+        self.status
+    }
+
+    pub fn set_status(&mut self, status: u8) {
+        self.status = status;
+    }
+
+    // resp_remaining is how many response bytes are still unread.
+    pub fn resp_remaining(&self) -> usize {
+        self.resp.len()
+    }
+
+    // peek_arg gives the queued arg at index without taking it.
+    pub fn peek_arg(&self, index: usize) -> Option<&[u8]> {
+        self.args.get(index).map(|arg| arg.as_slice())
+    }
+
+    // args iterates over the queued args without taking them.
+    pub fn args(&self) -> impl Iterator<Item = &[u8]> {
+        self.args.iter().map(|arg| arg.as_slice())
+    }
+
+    // arg_str gives the queued arg at index as text, replacing bad utf-8.
+    pub fn arg_str(&self, index: usize) -> Option<Cow<'_, str>> {
+        self.args.get(index).map(|arg| String::from_utf8_lossy(arg))
+    }
+
+    // arg_utf8 gives the queued arg at index as text, or an error if it isn't
+    // utf-8. like arg_str it gives None if there is no arg at index.
+    pub fn arg_utf8(&self, index: usize) -> Option<Result<&str, Utf8Error>> {
+        self.args.get(index).map(|arg| str::from_utf8(arg))
+    }
+
+    // add_arg queues an arg. args can't be added while a response is pending.
+    pub fn add_arg(&mut self, dat: Vec<u8>) -> Result<(), TransError> {
+        if !self.arg_mode() {
+            return Err(TransError::NotInArgMode);
+        }
+        if self.args.len() >= self.max_args {
+            return Err(TransError::LimitExceeded);
+        }
         self.args.push(dat);
+        Ok(())
     }
 
-    // take_args takes all the args if there are at least n, returning the first n.
+    // take_args takes the first n args if there are at least n, leaving the rest queued.
     pub fn take_args(&mut self, n: usize) -> Option<Vec<Vec<u8>>> {
         if self.args.len() >= n {
-            let mut args = std::mem::take(&mut self.args);
-            args.truncate(n);
-            Some(args)
+            let rest = self.args.split_off(n);
+            Some(std::mem::replace(&mut self.args, rest))
         } else {
             None
         }
     }
 
-    pub fn set_resp(&mut self, dat: Vec<u8>) {
-        // TODO error if not in arg mode
+    // take_up_to takes the first n args, or all of them if there are fewer.
+    pub fn take_up_to(&mut self, n: usize) -> Vec<Vec<u8>> {
+        let rest = self.args.split_off(cmp::min(n, self.args.len()));
+        std::mem::replace(&mut self.args, rest)
+    }
+
+    // set_resp appends to the response once all args have been taken.
+    pub fn set_resp(&mut self, dat: Vec<u8>) -> Result<(), TransError> {
+        if !self.args.is_empty() {
+            return Err(TransError::ArgsPending);
+        }
+        if self.resp.len() + dat.len() > self.max_resp_bytes {
+            return Err(TransError::LimitExceeded);
+        }
         self.resp.extend(dat);
+        self.resp.make_contiguous();
+        self.phase = Phase::Resp;
+        Ok(())
+    }
+
+    // peek_resp gives up to n bytes from the front of the response without taking them.
+    pub fn peek_resp(&self, n: usize) -> &[u8] {
+        let (front, _) = self.resp.as_slices();
+        &front[..cmp::min(n, front.len())]
     }
 
     // read_resp takes up to n bytes from the response.
-    // this could be more efficient.
     pub fn read_resp(&mut self, n: usize) -> Vec<u8> {
         let n = cmp::min(n, self.resp.len());
-        let (hd, tl) = self.resp.split_at(n);
-        let res = hd.to_vec();
-        self.resp = tl.to_vec();
-        return res;
+        self.resp.drain(..n).collect()
+    }
+
+    // take_resp takes all of the response that is left.
+    pub fn take_resp(&mut self) -> Vec<u8> {
+        Vec::from(std::mem::take(&mut self.resp))
+    }
+
+    // encode writes the transaction as a big endian u32 arg count, each arg as
+    // a u32 length and its bytes, then a u8 status, a u32 response length and
+    // the response.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.write_to(&mut buf).unwrap(); // writing to a Vec can't fail
+        buf
+    }
+
+    // write_to streams the encoding of the transaction to w.
+    pub fn write_to(&self, w: &mut impl io::Write) -> io::Result<()> {
+        w.write_all(&(self.args.len() as u32).to_be_bytes())?;
+        for arg in &self.args {
+            w.write_all(&(arg.len() as u32).to_be_bytes())?;
+            w.write_all(arg)?;
+        }
+        let (front, back) = self.resp.as_slices();
+        w.write_all(&[self.status])?;
+        w.write_all(&(self.resp.len() as u32).to_be_bytes())?;
+        w.write_all(front)?;
+        w.write_all(back)
+    }
+
+    // decode parses a transaction written by encode, held to the default limits.
+    pub fn decode(buf: &[u8]) -> Result<Trans, TransError> {
+        Self::decode_with_limits(buf, DEFAULT_MAX_ARGS, DEFAULT_MAX_RESP_BYTES)
+    }
+
+    // decode_with_limits is decode for a transaction made by with_limits.
+    // input with more args or a bigger response fails with LimitExceeded,
+    // checked before any of it is read.
+    pub fn decode_with_limits(
+        mut buf: &[u8],
+        max_args: usize,
+        max_resp_bytes: usize,
+    ) -> Result<Trans, TransError> {
+        let nargs = take_u32(&mut buf)?;
+        if nargs as usize > max_args {
+            return Err(TransError::LimitExceeded);
+        }
+        let mut args = Vec::new();
+        for _ in 0..nargs {
+            let n = take_u32(&mut buf)?;
+            args.push(take(&mut buf, n as usize)?.to_vec());
+        }
+        let status = take(&mut buf, 1)?[0];
+        let n = take_u32(&mut buf)?;
+        if n as usize > max_resp_bytes {
+            return Err(TransError::LimitExceeded);
+        }
+        let resp = take(&mut buf, n as usize)?.iter().copied().collect();
+        if !buf.is_empty() {
+            return Err(TransError::TrailingData);
+        }
+        Ok(Trans {
+            max_args,
+            max_resp_bytes,
+            ..Trans::from_wire(args, resp, status)
+        })
+    }
+
+    // read_from reads one encoded transaction from r.
+    pub fn read_from(r: &mut impl io::Read) -> io::Result<Trans> {
+        Self::read_from_max(r, MAX_FRAME_LEN)
+    }
+
+    // read_from_max reads one encoded transaction from r, refusing any arg or
+    // response longer than max so a peer can't make us allocate too much.
+    // all of them together are held to MAX_TRANS_LEN.
+    pub fn read_from_max(r: &mut impl io::Read, max: usize) -> io::Result<Trans> {
+        Self::read_from_limits(r, max, MAX_TRANS_LEN)
+    }
+
+    // read_from_limits is read_from_max with max_total bytes allowed across
+    // the args and response. past that, or past the arg count or response
+    // size a new Trans holds, it fails with LimitExceeded before reading on.
+    pub fn read_from_limits(
+        r: &mut impl io::Read,
+        max: usize,
+        max_total: usize,
+    ) -> io::Result<Trans> {
+        let limits = Trans::new();
+        let nargs = read_u32(r)?;
+        if nargs as usize > limits.max_args {
+            return Err(limit_exceeded());
+        }
+        let mut left = max_total;
+        let mut args = Vec::new();
+        for _ in 0..nargs {
+            let arg = read_frame(r, max, left)?;
+            left -= arg.len();
+            args.push(arg);
+        }
+        let mut status = [0];
+        r.read_exact(&mut status)?;
+        let resp = read_frame(r, max, cmp::min(left, limits.max_resp_bytes))?.into();
+        Ok(Trans::from_wire(args, resp, status[0]))
+    }
+
+    // from_wire makes a decoded transaction, in the response phase if it
+    // carries a response.
+    fn from_wire(args: Vec<Vec<u8>>, resp: VecDeque<u8>, status: u8) -> Trans {
+        let phase = if resp.is_empty() {
+            Phase::Args
+        } else {
+            Phase::Resp
+        };
+        Trans {
+            args,
+            resp,
+            status,
+            phase,
+            ..Trans::new()
+        }
+    }
+
+    // read_resp_into fills buf from the response like io::Read::read,
+    // returning how many bytes were copied.
+    pub fn read_resp_into(&mut self, buf: &mut [u8]) -> usize {
+        let n = cmp::min(buf.len(), self.resp.len());
+        for (dst, src) in buf.iter_mut().zip(self.resp.drain(..n)) {
+            *dst = src;
+        }
+        n
+    }
+
+    // into_parts gives up the queued args and the unread response.
+    pub fn into_parts(self) -> (Vec<Vec<u8>>, Vec<u8>) {
+        (self.args, Vec::from(self.resp))
+    }
+}
+
+// TransBuilder puts together a transaction one piece at a time, as in
+// TransBuilder::new().arg("ls").arg("/").build().
+#[derive(Debug, Default)]
+pub struct TransBuilder {
+    trans: Trans,
+}
+
+impl TransBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // arg queues another arg.
+    pub fn arg(mut self, dat: impl Into<Vec<u8>>) -> Self {
+        self.trans.args.push(dat.into());
+        self
+    }
+
+    // args queues each of args in turn.
+    pub fn args<A: Into<Vec<u8>>>(self, args: impl IntoIterator<Item = A>) -> Self {
+        args.into_iter().fold(self, |b, dat| b.arg(dat))
+    }
+
+    // resp appends to the response. unlike set_resp this may be mixed with
+    // args, as a transaction on the wire can carry both.
+    pub fn resp(mut self, dat: impl Into<Vec<u8>>) -> Self {
+        self.trans.resp.extend(dat.into());
+        self.trans.resp.make_contiguous();
+        self.trans.phase = Phase::Resp;
+        self
+    }
+
+    pub fn build(self) -> Trans {
+        self.trans
+    }
+}
+
+// Batch is a run of transactions sent together in one frame, so several
+// commands cost one round trip.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Batch {
+    pub trans: Vec<Trans>,
+}
+
+impl Batch {
+    pub fn new(trans: Vec<Trans>) -> Self {
+        Batch { trans }
+    }
+
+    // encode writes a big endian u32 count of transactions, then each one's
+    // encoding prefixed by its u32 length.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = (self.trans.len() as u32).to_be_bytes().to_vec();
+        for trans in &self.trans {
+            let enc = trans.encode();
+            buf.extend_from_slice(&(enc.len() as u32).to_be_bytes());
+            buf.extend_from_slice(&enc);
+        }
+        buf
+    }
+
+    // decode parses a batch written by encode.
+    pub fn decode(mut buf: &[u8]) -> Result<Batch, TransError> {
+        let count = take_u32(&mut buf)?;
+        let mut trans = Vec::new();
+        for _ in 0..count {
+            let n = take_u32(&mut buf)?;
+            trans.push(Trans::decode(take(&mut buf, n as usize)?)?);
+        }
+        if !buf.is_empty() {
+            return Err(TransError::TrailingData);
+        }
+        Ok(Batch { trans })
+    }
+}
+
+// take splits n bytes off the front of buf.
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> Result<&'a [u8], TransError> {
+    if buf.len() < n {
+        return Err(TransError::Truncated);
+    }
+    let (hd, tl) = buf.split_at(n);
+    *buf = tl;
+    Ok(hd)
+}
+
+fn take_u32(buf: &mut &[u8]) -> Result<u32, TransError> {
+    let bs = take(buf, 4)?;
+    Ok(u32::from_be_bytes(bs.try_into().unwrap()))
+}
+
+fn limit_exceeded() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, TransError::LimitExceeded)
+}
+
+fn read_u32(r: &mut impl io::Read) -> io::Result<u32> {
+    let mut bs = [0; 4];
+    r.read_exact(&mut bs)?;
+    Ok(u32::from_be_bytes(bs))
+}
+
+// read_frame reads a u32 length and that many bytes. a frame over max is
+// refused, as is one bigger than the left bytes the transaction has room for.
+fn read_frame(r: &mut impl io::Read, max: usize, left: usize) -> io::Result<Vec<u8>> {
+    let n = read_u32(r)? as usize;
+    if n > max {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {n} bytes is over the {max} byte limit"),
+        ));
+    }
+    if n > left {
+        return Err(limit_exceeded());
+    }
+    let mut buf = vec![0; n];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::trans::*;
+
+    #[test]
+    fn test_take_args() {
+        let mut trans = Trans::new();
+        for arg in ["a", "b", "c", "d"] {
+            trans.add_arg(arg.as_bytes().to_vec()).unwrap();
+        }
+        assert_eq!(trans.take_args(2), Some(vec![b"a".to_vec(), b"b".to_vec()]));
+        assert_eq!(trans.take_args(2), Some(vec![b"c".to_vec(), b"d".to_vec()]));
+        assert_eq!(trans.take_args(1), None);
+    }
+
+    #[test]
+    fn test_take_up_to() {
+        let mut trans = Trans::new();
+        trans.add_arg(b"a".to_vec()).unwrap();
+        assert_eq!(trans.take_up_to(3), vec![b"a".to_vec()]);
+        assert_eq!(trans.num_args(), 0);
+        assert_eq!(trans.take_up_to(3), Vec::<Vec<u8>>::new());
+
+        for arg in ["b", "c", "d"] {
+            trans.add_arg(arg.as_bytes().to_vec()).unwrap();
+        }
+        assert_eq!(trans.take_up_to(2), vec![b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(trans.take_up_to(0), Vec::<Vec<u8>>::new());
+        assert_eq!(trans.num_args(), 1);
+    }
+
+    #[test]
+    fn test_counts() {
+        let mut trans = Trans::new();
+        assert_eq!((trans.num_args(), trans.resp_remaining()), (0, 0));
+        trans.add_arg(b"a".to_vec()).unwrap();
+        trans.add_arg(b"b".to_vec()).unwrap();
+        assert_eq!(trans.num_args(), 2);
+        trans.take_args(2).unwrap();
+        assert_eq!(trans.num_args(), 0);
+
+        trans.set_resp(b"HELLO".to_vec()).unwrap();
+        assert_eq!(trans.resp_remaining(), 5);
+        trans.read_resp(3);
+        assert_eq!(trans.resp_remaining(), 2);
+        trans.read_resp(3);
+        assert_eq!(trans.resp_remaining(), 0);
+    }
+
+    #[test]
+    fn test_encode() {
+        let trans = Trans {
+            args: vec![b"hello".to_vec(), b"world".to_vec()],
+            resp: VecDeque::from(b"HELLO".to_vec()),
+            phase: Phase::Resp,
+            ..Trans::new()
+        };
+        let buf = trans.encode();
+        assert_eq!(buf.len(), 4 + 9 + 9 + 1 + 4 + 5);
+        assert_eq!(&buf[..8], b"\0\0\0\x02\0\0\0\x05");
+        assert_eq!(Trans::decode(&buf), Ok(trans.clone()));
+
+        let mut failed = trans;
+        failed.set_status(7);
+        let got = Trans::decode(&failed.encode()).unwrap();
+        assert_eq!(got.status(), 7);
+        assert_eq!(got, failed);
+        assert_eq!(Trans::decode(&Trans::new().encode()), Ok(Trans::new()));
+
+        for n in 0..buf.len() {
+            assert_eq!(Trans::decode(&buf[..n]), Err(TransError::Truncated));
+        }
+        let mut long = buf.clone();
+        long.push(0);
+        assert_eq!(Trans::decode(&long), Err(TransError::TrailingData));
+    }
+
+    #[test]
+    fn test_builder() {
+        let trans = TransBuilder::new().arg("cat").arg(b"/f1".to_vec()).build();
+        assert_eq!(trans.num_args(), 2);
+        assert_eq!(trans.resp_remaining(), 0);
+        assert_eq!(
+            trans.encode(),
+            b"\0\0\0\x02\0\0\0\x03cat\0\0\0\x03/f1\0\0\0\0\0".to_vec()
+        );
+
+        let trans = TransBuilder::new().arg("a").resp("HE").resp("LLO").build();
+        assert_eq!(trans.peek_arg(0), Some(&b"a"[..]));
+        assert_eq!(trans.peek_resp(5), b"HELLO");
+        assert_eq!(TransBuilder::new().build(), Trans::new());
+
+        let trans = TransBuilder::new().arg("ls").args(["/a", "/b"]).build();
+        assert_eq!(trans.args().collect::<Vec<_>>(), [&b"ls"[..], b"/a", b"/b"]);
+    }
+
+    #[test]
+    fn test_batch() {
+        let batch = Batch::new(vec![
+            TransBuilder::new().arg("mkdir").arg("/a").build(),
+            TransBuilder::new().arg("ls").arg("/").resp("a/\n").build(),
+            Trans::new(),
+        ]);
+        let enc = batch.encode();
+        assert_eq!(&enc[..4], &[0, 0, 0, 3]);
+        assert_eq!(Batch::decode(&enc), Ok(batch));
+        assert_eq!(
+            Batch::decode(&Batch::default().encode()),
+            Ok(Batch::default())
+        );
+
+        assert_eq!(
+            Batch::decode(&enc[..enc.len() - 1]),
+            Err(TransError::Truncated)
+        );
+        let mut long = enc.clone();
+        long.push(0);
+        assert_eq!(Batch::decode(&long), Err(TransError::TrailingData));
+        // a transaction can't run past the length it was given.
+        let mut short = enc;
+        short[7] -= 1;
+        assert_eq!(Batch::decode(&short), Err(TransError::Truncated));
+    }
+
+    #[test]
+    fn test_read_from() {
+        let trans = Trans {
+            args: vec![b"hello".to_vec(), b"world".to_vec()],
+            resp: VecDeque::from(b"HELLO".to_vec()),
+            phase: Phase::Resp,
+            ..Trans::new()
+        };
+        let mut buf = trans.encode();
+        buf.extend(Trans::new().encode());
+        let mut r = io::Cursor::new(&buf);
+        assert_eq!(Trans::read_from(&mut r).unwrap(), trans);
+        assert_eq!(Trans::read_from(&mut r).unwrap(), Trans::new());
+
+        let short = &trans.encode()[..20];
+        let err = Trans::read_from(&mut io::Cursor::new(short)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        // the limit is checked before anything is allocated.
+        let huge = [0, 0, 0, 1, 0xff, 0xff, 0xff, 0xff];
+        let err = Trans::read_from(&mut io::Cursor::new(huge)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = Trans::read_from_max(&mut io::Cursor::new(&buf), 4).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // a response over the frame limit is refused like an arg, and one
+        // past what the args left of the total is over the limit.
+        let big = Trans {
+            resp: VecDeque::from(b"HELLO THERE".to_vec()),
+            ..trans.clone()
+        };
+        let buf = big.encode();
+        let err = Trans::read_from_max(&mut io::Cursor::new(&buf), 8).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let limit = |err: io::Error| *err.into_inner().unwrap().downcast::<TransError>().unwrap();
+        let err = Trans::read_from_limits(&mut io::Cursor::new(&buf), 16, 20).unwrap_err();
+        assert_eq!(limit(err), TransError::LimitExceeded);
+        let err = Trans::read_from_limits(&mut io::Cursor::new(&buf), 16, 8).unwrap_err();
+        assert_eq!(limit(err), TransError::LimitExceeded);
+        let got = Trans::read_from_limits(&mut io::Cursor::new(&buf), 16, 21).unwrap();
+        assert_eq!(got, big);
+    }
+
+    #[test]
+    fn test_write_to() {
+        let trans = Trans {
+            args: vec![b"hello".to_vec(), b"world".to_vec()],
+            resp: VecDeque::from(b"HELLO".to_vec()),
+            phase: Phase::Resp,
+            ..Trans::new()
+        };
+        let mut buf = Vec::new();
+        trans.write_to(&mut buf).unwrap();
+        assert_eq!(buf, trans.encode());
+        let got = Trans::read_from(&mut io::Cursor::new(&buf)).unwrap();
+        assert_eq!(got, trans);
+    }
+
+    #[test]
+    fn test_peek_arg() {
+        let mut trans = Trans::new();
+        trans.add_arg(b"cat".to_vec()).unwrap();
+        trans.add_arg(b"/f".to_vec()).unwrap();
+        assert_eq!(trans.peek_arg(0), Some(&b"cat"[..]));
+        assert_eq!(trans.peek_arg(1), Some(&b"/f"[..]));
+        assert_eq!(trans.peek_arg(2), None);
+        assert_eq!(trans.num_args(), 2);
+        assert_eq!(trans.take_args(1), Some(vec![b"cat".to_vec()]));
+        assert_eq!(trans.peek_arg(0), Some(&b"/f"[..]));
+    }
+
+    #[test]
+    fn test_args() {
+        let mut trans = Trans::new();
+        assert_eq!(trans.args().count(), 0);
+        for arg in ["cp", "a", "b"] {
+            trans.add_arg(arg.as_bytes().to_vec()).unwrap();
+        }
+        let args: Vec<&[u8]> = trans.args().collect();
+        assert_eq!(args, vec![&b"cp"[..], b"a", b"b"]);
+
+        // iterating leaves the args queued.
+        assert_eq!(trans.take_args(1), Some(vec![b"cp".to_vec()]));
+        assert_eq!(trans.args().collect::<Vec<_>>(), vec![&b"a"[..], b"b"]);
+    }
+
+    #[test]
+    fn test_arg_str() {
+        let mut trans = Trans::new();
+        trans.add_arg(b"hello".to_vec()).unwrap();
+        trans.add_arg(b"bad\xff".to_vec()).unwrap();
+
+        assert_eq!(trans.arg_str(0).unwrap(), "hello");
+        assert_eq!(trans.arg_utf8(0), Some(Ok("hello")));
+        assert_eq!(trans.arg_str(1).unwrap(), "bad\u{fffd}");
+        assert!(trans.arg_utf8(1).unwrap().is_err());
+        assert_eq!(trans.arg_str(2), None);
+        assert_eq!(trans.arg_utf8(2), None);
+        assert_eq!(trans.num_args(), 2);
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut trans = Trans::with_limits(2, 5);
+        trans.add_arg(b"a".to_vec()).unwrap();
+        trans.add_arg(b"b".to_vec()).unwrap();
+        assert_eq!(trans.add_arg(b"c".to_vec()), Err(TransError::LimitExceeded));
+        assert_eq!(trans.num_args(), 2);
+        trans.take_args(2).unwrap();
+
+        trans.set_resp(b"HEL".to_vec()).unwrap();
+        trans.set_resp(b"LO".to_vec()).unwrap();
+        assert_eq!(
+            trans.set_resp(b"!".to_vec()),
+            Err(TransError::LimitExceeded)
+        );
+        assert_eq!(trans.read_resp(5), b"HELLO");
+
+        // reading makes room again.
+        trans.set_resp(b"HELLO".to_vec()).unwrap();
+
+        // the limits hold for decoded input too.
+        let enc = TransBuilder::new()
+            .arg("a")
+            .arg("b")
+            .arg("c")
+            .build()
+            .encode();
+        assert_eq!(
+            Trans::decode_with_limits(&enc, 2, 5),
+            Err(TransError::LimitExceeded)
+        );
+        let trans = Trans::decode_with_limits(&enc, 3, 5).unwrap();
+        assert_eq!(trans.num_args(), 3);
+        assert_eq!(
+            trans,
+            Trans {
+                max_args: 3,
+                max_resp_bytes: 5,
+                ..Trans::decode(&enc).unwrap()
+            }
+        );
+        let enc = TransBuilder::new().resp("HELLO!").build().encode();
+        assert_eq!(
+            Trans::decode_with_limits(&enc, 2, 5),
+            Err(TransError::LimitExceeded)
+        );
+
+        // a huge arg count is refused up front, not looped over.
+        let huge = [0xff, 0xff, 0xff, 0xff];
+        assert_eq!(Trans::decode(&huge), Err(TransError::LimitExceeded));
+        let err = Trans::read_from(&mut io::Cursor::new(huge)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "transaction limit exceeded");
+    }
+
+    #[test]
+    fn test_modes() {
+        let mut trans = Trans::new();
+        trans.add_arg(b"a".to_vec()).unwrap();
+        assert_eq!(
+            trans.set_resp(b"HELLO".to_vec()),
+            Err(TransError::ArgsPending)
+        );
+        trans.take_args(1).unwrap();
+        trans.set_resp(b"HELLO".to_vec()).unwrap();
+        assert!(!trans.arg_mode());
+        assert_eq!(trans.add_arg(b"b".to_vec()), Err(TransError::NotInArgMode));
+
+        trans.read_resp(5);
+        assert_eq!(trans.add_arg(b"b".to_vec()), Err(TransError::NotInArgMode));
+        trans.reset();
+        trans.add_arg(b"b".to_vec()).unwrap();
+        assert_eq!(trans.take_args(1), Some(vec![b"b".to_vec()]));
+    }
+
+    #[test]
+    fn test_phase() {
+        let mut trans = Trans::new();
+        trans.set_resp(b"HI".to_vec()).unwrap();
+        assert_eq!(trans.read_resp(2), b"HI");
+
+        // a drained response still counts as a response.
+        assert!(!trans.arg_mode());
+        assert_eq!(trans.add_arg(b"a".to_vec()), Err(TransError::NotInArgMode));
+        trans.set_resp(Vec::new()).unwrap();
+        assert!(!trans.arg_mode());
+        trans.reset();
+        assert!(trans.arg_mode());
+        trans.add_arg(b"a".to_vec()).unwrap();
+
+        // so does an empty one.
+        trans.take_args(1).unwrap();
+        trans.set_resp(Vec::new()).unwrap();
+        assert!(!trans.arg_mode());
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut trans = Trans::new();
+        trans.add_arg(b"a".to_vec()).unwrap();
+        trans.add_arg(b"b".to_vec()).unwrap();
+        trans.take_args(1).unwrap();
+        assert_eq!(trans.set_resp(b"HI".to_vec()), Err(TransError::ArgsPending));
+        trans.take_args(1).unwrap();
+        trans.set_resp(b"HELLO".to_vec()).unwrap();
+        trans.set_status(1);
+        trans.read_resp(2);
+        let caps = (trans.args.capacity(), trans.resp.capacity());
+
+        trans.reset();
+        assert!(trans.arg_mode());
+        assert_eq!(trans.status(), STATUS_OK);
+        assert_eq!(trans.take_args(1), None);
+        assert_eq!(trans.read_resp(5), b"");
+        assert_eq!((trans.args.capacity(), trans.resp.capacity()), caps);
+    }
+
+    #[test]
+    fn test_read_resp() {
+        let mut trans = Trans::new();
+        trans.set_resp(b"HELLO".to_vec()).unwrap();
+        assert_eq!(trans.read_resp(2), b"HE");
+        trans.set_resp(b"!".to_vec()).unwrap();
+        assert_eq!(trans.read_resp(10), b"LLO!");
+        assert_eq!(trans.read_resp(1), b"");
+        assert!(!trans.arg_mode());
+
+        // draining a byte at a time used to be quadratic.
+        trans.reset();
+        trans.set_resp(vec![7; 1 << 20]).unwrap();
+        let mut total = 0;
+        while trans.resp_remaining() > 0 {
+            total += trans.read_resp(1).len();
+        }
+        assert_eq!(total, 1 << 20);
+    }
+
+    #[test]
+    fn test_take_resp() {
+        let mut trans = Trans::new();
+        trans.set_resp(b"HELLO".to_vec()).unwrap();
+        assert_eq!(trans.read_resp(2), b"HE");
+        assert_eq!(trans.take_resp(), b"LLO");
+        assert_eq!(trans.resp_remaining(), 0);
+        assert_eq!(trans.take_resp(), b"");
+    }
+
+    #[test]
+    fn test_peek_resp() {
+        let mut trans = Trans::new();
+        trans.set_resp(b"HELLO".to_vec()).unwrap();
+        assert_eq!(trans.peek_resp(3), b"HEL");
+        assert_eq!(trans.read_resp(3), b"HEL");
+        assert_eq!(trans.peek_resp(3), b"LO");
+
+        // the response may wrap around inside the deque after reads.
+        trans.set_resp(b" WORLD".repeat(10)).unwrap();
+        assert_eq!(trans.peek_resp(6), b"LO WOR");
+        assert_eq!(trans.peek_resp(100).len(), 62);
+    }
+
+    #[test]
+    fn test_read_resp_into() {
+        let mut trans = Trans::new();
+        trans.set_resp(b"HELLO".to_vec()).unwrap();
+        let mut buf = [0; 3];
+        assert_eq!(trans.read_resp_into(&mut buf), 3);
+        assert_eq!(&buf, b"HEL");
+        assert_eq!(trans.read_resp_into(&mut buf), 2);
+        assert_eq!(&buf[..2], b"LO");
+        assert_eq!(trans.read_resp_into(&mut buf), 0);
+    }
+
+    #[test]
+    fn test_into_parts() {
+        let mut trans = Trans::new();
+        trans.add_arg(b"cat".to_vec()).unwrap();
+        trans.add_arg(b"/f".to_vec()).unwrap();
+        let want = vec![b"cat".to_vec(), b"/f".to_vec()];
+        assert_eq!(trans.into_parts(), (want, Vec::new()));
+
+        // only the unread part of the response is given back.
+        let mut trans = Trans::new();
+        trans.set_resp(b"HELLO".to_vec()).unwrap();
+        trans.read_resp(1);
+        assert_eq!(trans.into_parts(), (Vec::new(), b"ELLO".to_vec()));
     }
 }